@@ -1,2 +1,2 @@
 #! /bin/bash
-cargo install --path="." --features="tokio,server,futures-util"
+cargo install --path="." --features="tokio,tokio-util,server,futures-util"
@@ -4,13 +4,12 @@ blockchain-sim-test-server &
 
 sleep 0.5
 
-blockchain-sim-test-client send_transactions
-
-sleep 2
-
-blockchain-sim-test-client count_transactions
+# Fails if not all transactions were confirmed
+blockchain-sim-load-generator --transactions 1000 --rate 1000
 result=$?
 
+# The server shuts down gracefully on SIGTERM
 killall blockchain-sim-test-server
+wait
 
 exit $result
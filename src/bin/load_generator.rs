@@ -0,0 +1,303 @@
+//! Generates load on a blockchain-sim server and reports throughput and latency
+//!
+//! In open-loop mode, transactions are submitted at a fixed rate regardless of how fast the
+//! server confirms them. In closed-loop mode, a fixed number of transactions is outstanding at
+//! any time and a new one is only submitted once an earlier one was confirmed (or rejected).
+//!
+//! All keys are generated and all transactions are signed before the measurement starts,
+//! unless they are read from a file created by `blockchain-sim-presign`.
+
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+use blockchain_simulator::encoding::Encoding;
+use blockchain_simulator::workload::{
+    cached_accounts, generate_accounts, parallel_map, Summary, TransactionReader, WorkloadClient,
+};
+use blockchain_simulator::{AccountId, TestOperation, Transaction, DEFAULT_BLOCKCHAIN_PORT};
+
+/// How many transactions may be read ahead for each connection
+const READ_AHEAD: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Submit transactions at a fixed rate (see --rate)
+    Open,
+    /// Keep a fixed number of transactions outstanding (see --concurrency)
+    Closed,
+}
+
+#[derive(Parser)]
+#[clap(about = "Generates load on a blockchain-sim server and reports throughput and latency")]
+struct Args {
+    #[clap(
+        long,
+        short = 'a',
+        help = "The server to connect to [default: localhost:8080]"
+    )]
+    server_address: Option<String>,
+    #[clap(long, default_value = "bincode", help = "bincode, cbor, or json")]
+    encoding: Encoding,
+    #[clap(long, value_enum, default_value = "open")]
+    mode: Mode,
+    #[clap(
+        long,
+        default_value_t = 1000.0,
+        help = "Transactions per second to submit (open-loop only)"
+    )]
+    rate: f64,
+    #[clap(
+        long,
+        default_value_t = 100,
+        help = "Number of outstanding transactions (closed-loop only)"
+    )]
+    concurrency: usize,
+    #[clap(long, help = "Stop submitting after this many seconds")]
+    duration: Option<f64>,
+    #[clap(
+        long,
+        help = "Stop submitting after this many transactions (required for closed-loop runs without --presigned, as all transactions are signed up front)"
+    )]
+    transactions: Option<usize>,
+    #[clap(
+        long,
+        default_value_t = 16,
+        help = "Number of accounts to issue transactions from (ignored with --presigned)"
+    )]
+    accounts: usize,
+    #[clap(
+        long,
+        help = "File to load keys from, or to store them in if it does not hold enough"
+    )]
+    key_cache: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Submit the transactions of this file (created by blockchain-sim-presign) instead of signing new ones"
+    )]
+    presigned: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value_t = 4,
+        help = "Number of connections to submit transactions through"
+    )]
+    connections: usize,
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "How long to wait for confirmations after the last transaction was sent (in s)"
+    )]
+    timeout: u64,
+}
+
+impl Args {
+    /// The maximum number of transactions that will be submitted (None if unbounded)
+    fn max_transactions(&self) -> Option<usize> {
+        match (self.mode, self.transactions, self.duration) {
+            (_, Some(num), _) => Some(num),
+            (Mode::Open, None, Some(duration)) => Some((self.rate * duration).ceil() as usize),
+            _ => None,
+        }
+    }
+}
+
+type TransactionSource = Box<dyn Iterator<Item = Transaction<TestOperation>> + Send>;
+
+/// Signs all transactions up front
+fn sign_transactions(args: &Args) -> TransactionSource {
+    let num_transactions = match (args.max_transactions(), args.mode) {
+        (Some(num), _) => num,
+        (None, Mode::Open) => panic!("Need --duration or --transactions"),
+        (None, Mode::Closed) => panic!("Closed-loop runs need --transactions or --presigned"),
+    };
+
+    println!(
+        "Generating keys for {} accounts and signing {num_transactions} transactions",
+        args.accounts
+    );
+
+    let accounts = match &args.key_cache {
+        Some(path) => cached_accounts(path, args.accounts)
+            .unwrap_or_else(|err| panic!("Failed to use key cache {}: {err}", path.display())),
+        None => generate_accounts(args.accounts),
+    };
+
+    let transactions = parallel_map((0..num_transactions).collect(), |pos| {
+        let account = &accounts[pos % accounts.len()];
+        Transaction::new(
+            account.id,
+            TestOperation::Empty {},
+            account.private_key.clone(),
+        )
+    });
+
+    Box::new(transactions.into_iter())
+}
+
+/// Streams transactions from a file created by `blockchain-sim-presign`
+fn load_transactions(args: &Args, path: &Path) -> TransactionSource {
+    let reader = TransactionReader::open(path)
+        .unwrap_or_else(|err| panic!("Failed to open {}: {err}", path.display()));
+
+    let transactions = reader.map(|result| result.expect("Failed to read pre-signed transaction"));
+
+    match args.max_transactions() {
+        Some(num) => Box::new(transactions.take(num)),
+        None => Box::new(transactions),
+    }
+}
+
+/// Hands out transactions to the connections
+///
+/// All transactions of an account go through the same connection to preserve their order.
+/// Stops once the transactions run out or a connection does not accept any more.
+fn dispatch(
+    transactions: TransactionSource,
+    rate: f64,
+    queues: Vec<mpsc::Sender<(Duration, Transaction<TestOperation>)>>,
+) {
+    let mut connections = HashMap::<AccountId, usize>::new();
+
+    for (pos, transaction) in transactions.enumerate() {
+        let offset = Duration::from_secs_f64(pos as f64 / rate);
+
+        let num_accounts = connections.len();
+        let connection = *connections
+            .entry(*transaction.get_source())
+            .or_insert(num_accounts % queues.len());
+
+        if queues[connection]
+            .blocking_send((offset, transaction))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    assert!(args.rate > 0.0, "Rate must be positive");
+    assert!(args.concurrency > 0, "Concurrency must be positive");
+    assert!(args.accounts > 0, "Need at least one account");
+    assert!(args.connections > 0, "Need at least one connection");
+
+    let transactions = match &args.presigned {
+        Some(path) => load_transactions(&args, path),
+        None => sign_transactions(&args),
+    };
+
+    let duration = args.duration.map(Duration::from_secs_f64);
+
+    // Every connection needs at least one outstanding transaction in closed-loop mode
+    let mut num_connections = args.connections;
+    if args.presigned.is_none() {
+        num_connections = num_connections.min(args.accounts);
+    }
+    if args.mode == Mode::Closed {
+        num_connections = num_connections.min(args.concurrency);
+    }
+
+    let addr = args
+        .server_address
+        .clone()
+        .unwrap_or_else(|| format!("localhost:{DEFAULT_BLOCKCHAIN_PORT}"))
+        .to_socket_addrs()
+        .expect("Invalid server address")
+        .next()
+        .expect("Invalid server address");
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("Failed to start worker threads");
+
+    let summary = rt.block_on(async move {
+        let mut clients = Vec::new();
+        for _ in 0..num_connections {
+            let client = WorkloadClient::<TestOperation>::connect(addr, args.encoding)
+                .await
+                .expect("Failed to connect to server");
+            clients.push(Arc::new(client));
+        }
+
+        let (queues, receivers): (Vec<_>, Vec<_>) = (0..num_connections)
+            .map(|_| mpsc::channel(READ_AHEAD))
+            .unzip();
+
+        let rate = args.rate;
+        let dispatcher = tokio::task::spawn_blocking(move || dispatch(transactions, rate, queues));
+
+        let start = Instant::now();
+        let mut senders = Vec::new();
+
+        for (pos, (client, mut queue)) in clients.iter().zip(receivers).enumerate() {
+            let client = client.clone();
+
+            // Distribute the outstanding transactions evenly among connections
+            let window = args.concurrency / num_connections
+                + usize::from(pos < args.concurrency % num_connections);
+
+            senders.push(tokio::spawn(async move {
+                while let Some((offset, transaction)) = queue.recv().await {
+                    if duration.is_some_and(|duration| start.elapsed() >= duration) {
+                        break;
+                    }
+
+                    match args.mode {
+                        Mode::Open => sleep_until(start + offset).await,
+                        Mode::Closed => client.wait_for_pending_below(window).await,
+                    }
+
+                    client
+                        .submit(transaction)
+                        .await
+                        .expect("Failed to send transaction");
+                }
+            }));
+        }
+
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        dispatcher.await.unwrap();
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let num_sent: usize = clients
+            .iter()
+            .map(|client| client.summary().submitted)
+            .sum();
+        println!(
+            "Sent {num_sent} transactions in {elapsed:.3}s ({:.1} tx/s offered)",
+            num_sent as f64 / elapsed
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(args.timeout);
+        for client in &clients {
+            if !client
+                .wait_for_all(deadline.saturating_duration_since(Instant::now()))
+                .await
+            {
+                println!("Timed out waiting for confirmations");
+                break;
+            }
+        }
+
+        Summary::merge(clients.iter().map(|client| client.summary()))
+    });
+
+    println!("{summary}");
+
+    if summary.num_unconfirmed() > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Generates keys and signed transactions ahead of time, so benchmarks are not limited by client CPU
+//!
+//! The output can be passed to `blockchain-sim-load-generator --presigned`.
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use clap::Parser;
+
+use blockchain_simulator::workload::{cached_accounts, generate_accounts, presign_transactions};
+use blockchain_simulator::TestOperation;
+
+#[derive(Parser)]
+#[clap(about = "Writes pre-signed transactions for blockchain-sim benchmarks to a file")]
+struct Args {
+    #[clap(long, short = 'o', help = "File to write the transactions to")]
+    output: PathBuf,
+    #[clap(long, help = "Number of transactions to sign")]
+    transactions: usize,
+    #[clap(
+        long,
+        default_value_t = 16,
+        help = "Number of accounts to issue transactions from (in turn)"
+    )]
+    accounts: usize,
+    #[clap(
+        long,
+        help = "File to load keys from, or to store them in if it does not hold enough"
+    )]
+    key_cache: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    assert!(args.accounts > 0, "Need at least one account");
+
+    let start = Instant::now();
+    let accounts = match &args.key_cache {
+        Some(path) => cached_accounts(path, args.accounts)
+            .unwrap_or_else(|err| panic!("Failed to use key cache {}: {err}", path.display())),
+        None => generate_accounts(args.accounts),
+    };
+
+    println!(
+        "Got keys for {} accounts after {:.3}s",
+        accounts.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    let start = Instant::now();
+    presign_transactions(&args.output, &accounts, args.transactions, |_| {
+        TestOperation::Empty {}
+    })
+    .unwrap_or_else(|err| panic!("Failed to write {}: {err}", args.output.display()));
+
+    println!(
+        "Signed {} transactions in {:.3}s",
+        args.transactions,
+        start.elapsed().as_secs_f64()
+    );
+}
@@ -0,0 +1,271 @@
+//! Replays the transactions of a server trace (see `--trace`) against a running server
+//!
+//! Transactions are submitted with the same inter-arrival times (optionally scaled) and exactly
+//! as they were recorded, including their signatures. With `--resign`, every source account of
+//! the trace is instead simulated by a generated (or cached, see `--key-cache`) account that signs
+//! the recorded operations again.
+
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+
+use tokio::time::{sleep_until, Instant};
+
+use blockchain_simulator::encoding::Encoding;
+use blockchain_simulator::server::trace::{read_trace, TraceEvent, TraceFormat};
+use blockchain_simulator::workload::{
+    cached_accounts, generate_accounts, parallel_map, Account, Summary, WorkloadClient,
+};
+use blockchain_simulator::{
+    AccountId, TestOperation, Transaction, TxPayload, DEFAULT_BLOCKCHAIN_PORT,
+};
+
+#[derive(Parser)]
+#[clap(about = "Replays a recorded transaction trace against a blockchain-sim server")]
+struct Args {
+    #[clap(help = "Trace recorded by the server using --trace")]
+    trace: PathBuf,
+    #[clap(
+        long,
+        help = "Format of the trace [default: csv for *.csv files, json otherwise]",
+        value_enum
+    )]
+    trace_format: Option<TraceFormat>,
+    #[clap(
+        long,
+        short = 'a',
+        help = "The server to connect to [default: localhost:8080]"
+    )]
+    server_address: Option<String>,
+    #[clap(long, default_value = "bincode", help = "bincode, cbor, or json")]
+    encoding: Encoding,
+    #[clap(
+        long,
+        default_value_t = 1.0,
+        help = "Multiplies all inter-arrival times (e.g., 0.5 replays twice as fast)"
+    )]
+    time_scale: f64,
+    #[clap(
+        long,
+        help = "Sign the recorded operations with generated accounts instead of submitting the recorded transactions"
+    )]
+    resign: bool,
+    #[clap(
+        long,
+        help = "Map the sources of the trace onto this many accounts [default: one per source]",
+        requires = "resign"
+    )]
+    accounts: Option<usize>,
+    #[clap(
+        long,
+        help = "File to load keys from, or to store them in if it does not hold enough",
+        requires = "resign"
+    )]
+    key_cache: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value_t = 4,
+        help = "Number of connections to submit transactions through"
+    )]
+    connections: usize,
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "How long to wait for confirmations after the last transaction was sent (in s)"
+    )]
+    timeout: u64,
+}
+
+/// A transaction of the trace, relative to the first one
+struct ReplayItem {
+    offset: Duration,
+    /// Position of the source among all sources of the trace
+    source: usize,
+    transaction: Transaction<TestOperation>,
+}
+
+/// Signs the operation of a recorded transaction with a generated account
+///
+/// Cost and validity are kept. Returns None for transactions that do not carry an operation
+/// (e.g., account creation), as those only make sense for the original account.
+fn resign(
+    transaction: &Transaction<TestOperation>,
+    account: &Account,
+) -> Option<Transaction<TestOperation>> {
+    let TxPayload::Operation { operation } = transaction.get_payload() else {
+        return None;
+    };
+
+    Some(Transaction::new_with_validity(
+        account.id,
+        operation.clone(),
+        transaction.get_cost(),
+        *transaction.get_validity(),
+        account.private_key.clone(),
+    ))
+}
+
+fn main() {
+    let args = Args::parse();
+
+    assert!(args.time_scale >= 0.0, "Time scale must not be negative");
+    assert!(args.connections > 0, "Need at least one connection");
+
+    let format = args
+        .trace_format
+        .unwrap_or_else(|| TraceFormat::from_path(&args.trace));
+    let trace = read_trace(&args.trace, format)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", args.trace.display()));
+
+    let mut sources = HashMap::<AccountId, usize>::new();
+    let mut items = Vec::new();
+    let mut start_us = None;
+
+    for entry in trace {
+        let TraceEvent::TransactionReceived { source, data, .. } = entry.event else {
+            continue;
+        };
+
+        let transaction = bincode::deserialize(&data).unwrap_or_else(|err| {
+            panic!("Failed to decode recorded transaction (was it a TestOperation?): {err}")
+        });
+
+        let start_us = *start_us.get_or_insert(entry.time_us);
+        let offset = Duration::from_micros(entry.time_us - start_us).mul_f64(args.time_scale);
+
+        let num_sources = sources.len();
+        let source = *sources.entry(source).or_insert(num_sources);
+
+        items.push(ReplayItem {
+            offset,
+            source,
+            transaction,
+        });
+    }
+
+    let num_transactions = items.len();
+
+    let transactions: Vec<_> = if args.resign {
+        let num_accounts = args.accounts.unwrap_or(sources.len()).max(1);
+        println!(
+            "Replaying {num_transactions} transactions from {} sources using {num_accounts} accounts",
+            sources.len()
+        );
+
+        let accounts = match &args.key_cache {
+            Some(path) => cached_accounts(path, num_accounts)
+                .unwrap_or_else(|err| panic!("Failed to use key cache {}: {err}", path.display())),
+            None => generate_accounts(num_accounts),
+        };
+
+        // Signing is slow, so it must not affect the timing of the replay
+        let transactions: Vec<_> = parallel_map(items, |item| {
+            let account = item.source % num_accounts;
+            resign(&item.transaction, &accounts[account])
+                .map(|transaction| (item.offset, account, transaction))
+        })
+        .into_iter()
+        .flatten()
+        .collect();
+
+        println!(
+            "Signed all transactions (skipped {} without an operation)",
+            num_transactions - transactions.len()
+        );
+        transactions
+    } else {
+        println!(
+            "Replaying {num_transactions} transactions from {} sources",
+            sources.len()
+        );
+
+        items
+            .into_iter()
+            .map(|item| (item.offset, item.source, item.transaction))
+            .collect()
+    };
+
+    let addr = args
+        .server_address
+        .unwrap_or_else(|| format!("localhost:{DEFAULT_BLOCKCHAIN_PORT}"))
+        .to_socket_addrs()
+        .expect("Invalid server address")
+        .next()
+        .expect("Invalid server address");
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("Failed to start worker threads");
+
+    rt.block_on(async move {
+        let mut clients = Vec::new();
+        for _ in 0..args.connections {
+            let client = WorkloadClient::<TestOperation>::connect(addr, args.encoding)
+                .await
+                .expect("Failed to connect to server");
+            clients.push(Arc::new(client));
+        }
+
+        // All transactions of an account go through the same connection to preserve their order
+        let mut schedules = vec![Vec::new(); clients.len()];
+        for (offset, account, transaction) in transactions {
+            schedules[account % clients.len()].push((offset, transaction));
+        }
+
+        let start = Instant::now();
+        let mut senders = Vec::new();
+
+        for (client, schedule) in clients.iter().zip(schedules) {
+            let client = client.clone();
+
+            senders.push(tokio::spawn(async move {
+                let mut max_delay = Duration::ZERO;
+
+                for (offset, transaction) in schedule {
+                    sleep_until(start + offset).await;
+                    max_delay = max_delay.max(start.elapsed().saturating_sub(offset));
+
+                    client
+                        .submit(transaction)
+                        .await
+                        .expect("Failed to send transaction");
+                }
+
+                max_delay
+            }));
+        }
+
+        let mut max_delay = Duration::ZERO;
+        for sender in senders {
+            max_delay = max_delay.max(sender.await.unwrap());
+        }
+
+        println!(
+            "Sent all transactions after {:.3}s (at most {:.2}ms behind schedule)",
+            start.elapsed().as_secs_f64(),
+            max_delay.as_secs_f64() * 1000.0
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(args.timeout);
+        for client in &clients {
+            if !client
+                .wait_for_all(deadline.saturating_duration_since(Instant::now()))
+                .await
+            {
+                println!("Timed out waiting for confirmations");
+                break;
+            }
+        }
+
+        println!(
+            "{}",
+            Summary::merge(clients.iter().map(|client| client.summary()))
+        );
+    });
+}
@@ -0,0 +1,398 @@
+//! Smart-contract style execution using a tiny stack machine
+//!
+//! Transactions either deploy a program or call a deployed program with
+//! a list of arguments. Every contract has its own persistent storage, and
+//! every transaction produces a `Receipt` with the outcome of execution.
+//!
+//! All execution is deterministic, so clients that replay the ledger using
+//! `ContractState` end up with the same storage and receipts as the server.
+
+mod vm;
+pub use vm::{execute, Execution, ExecutionError, Instruction, Storage, Word};
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AccountId, StateError, StateMachine, Transaction, TransactionId, TxPayload};
+
+pub type ContractId = u64;
+
+/// Gas charged for deploying a contract (per instruction)
+const DEPLOY_GAS_PER_INSTRUCTION: u64 = 10;
+
+/// Upper bound for the gas limit of any transaction
+///
+/// Contracts run while the ledger is locked, so this bounds how long a single call can take.
+pub const MAX_GAS_PER_TRANSACTION: u64 = 1_000_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ContractOperation {
+    Deploy {
+        code: Vec<Instruction>,
+        gas_limit: u64,
+    },
+    Call {
+        contract: ContractId,
+        args: Vec<Word>,
+        gas_limit: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReceiptStatus {
+    Deployed {
+        contract: ContractId,
+    },
+    Returned {
+        value: Option<Word>,
+    },
+    Failed {
+        error: ExecutionError,
+    },
+    NoSuchContract {
+        contract: ContractId,
+    },
+    /// The gas limit exceeds the cost of the transaction (or `MAX_GAS_PER_TRANSACTION`)
+    GasLimitTooHigh {
+        gas_limit: u64,
+        max_gas: u64,
+    },
+}
+
+/// Outcome of a contract transaction
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Receipt {
+    pub status: ReceiptStatus,
+    pub gas_used: u64,
+    pub logs: Vec<Word>,
+}
+
+impl Receipt {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self.status,
+            ReceiptStatus::Deployed { .. } | ReceiptStatus::Returned { .. }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Contract {
+    pub owner: AccountId,
+    pub code: Vec<Instruction>,
+    pub storage: Storage,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ContractState {
+    next_contract_id: ContractId,
+    contracts: BTreeMap<ContractId, Contract>,
+    receipts: BTreeMap<TransactionId, Receipt>,
+}
+
+impl ContractState {
+    pub fn get_contract(&self, contract: &ContractId) -> Option<&Contract> {
+        self.contracts.get(contract)
+    }
+
+    pub fn get_receipt(&self, transaction: &TransactionId) -> Option<&Receipt> {
+        self.receipts.get(transaction)
+    }
+
+    /// Executes a transaction and records its receipt
+    ///
+    /// A transaction that was executed before is not executed again; its original receipt is
+    /// returned instead.
+    pub fn execute(&mut self, tx: &Transaction<ContractOperation>) -> &Receipt {
+        let id = tx.get_id();
+        if self.receipts.contains_key(&id) {
+            return &self.receipts[&id];
+        }
+
+        let receipt = match tx.get_payload() {
+            TxPayload::CreateAccount { .. }
+            | TxPayload::SetMultisigPolicy { .. }
+            | TxPayload::RotateKey { .. }
+            | TxPayload::DeactivateAccount => Receipt {
+                status: ReceiptStatus::Returned { value: None },
+                gas_used: 0,
+                logs: vec![],
+            },
+            TxPayload::Operation { operation } => self.execute_operation(tx, operation),
+        };
+
+        self.receipts.entry(id).or_insert(receipt)
+    }
+
+    fn execute_operation(
+        &mut self,
+        tx: &Transaction<ContractOperation>,
+        operation: &ContractOperation,
+    ) -> Receipt {
+        // Gas is paid for by the declared cost of the transaction
+        let (ContractOperation::Deploy { gas_limit, .. }
+        | ContractOperation::Call { gas_limit, .. }) = operation;
+        let max_gas = tx.get_cost().min(MAX_GAS_PER_TRANSACTION);
+
+        if *gas_limit > max_gas {
+            return Receipt {
+                status: ReceiptStatus::GasLimitTooHigh {
+                    gas_limit: *gas_limit,
+                    max_gas,
+                },
+                gas_used: 0,
+                logs: vec![],
+            };
+        }
+
+        match operation {
+            ContractOperation::Deploy { code, gas_limit } => {
+                let gas_used = DEPLOY_GAS_PER_INSTRUCTION * code.len() as u64;
+
+                if gas_used > *gas_limit {
+                    return Receipt {
+                        status: ReceiptStatus::Failed {
+                            error: ExecutionError::OutOfGas,
+                        },
+                        gas_used: *gas_limit,
+                        logs: vec![],
+                    };
+                }
+
+                let contract = self.next_contract_id;
+                self.next_contract_id += 1;
+
+                self.contracts.insert(
+                    contract,
+                    Contract {
+                        owner: *tx.get_source(),
+                        code: code.clone(),
+                        storage: Storage::new(),
+                    },
+                );
+
+                Receipt {
+                    status: ReceiptStatus::Deployed { contract },
+                    gas_used,
+                    logs: vec![],
+                }
+            }
+            ContractOperation::Call {
+                contract,
+                args,
+                gas_limit,
+            } => {
+                let Some(entry) = self.contracts.get_mut(contract) else {
+                    return Receipt {
+                        status: ReceiptStatus::NoSuchContract {
+                            contract: *contract,
+                        },
+                        gas_used: 0,
+                        logs: vec![],
+                    };
+                };
+
+                let caller = *tx.get_source() as Word;
+                let execution = execute(&entry.code, args, caller, &mut entry.storage, *gas_limit);
+
+                let status = match execution.result {
+                    Ok(value) => ReceiptStatus::Returned { value },
+                    Err(error) => ReceiptStatus::Failed { error },
+                };
+
+                Receipt {
+                    status,
+                    gas_used: execution.gas_used,
+                    logs: execution.logs,
+                }
+            }
+        }
+    }
+}
+
+impl StateMachine<ContractOperation> for ContractState {
+    /// Failed executions still consume gas and produce a receipt, but are reported as errors
+    fn apply(&mut self, tx: &Transaction<ContractOperation>) -> Result<(), StateError> {
+        match &self.execute(tx).status {
+            ReceiptStatus::Failed { error } => Err(Box::new(error.clone())),
+            ReceiptStatus::NoSuchContract { contract } => {
+                Err(format!("No such contract: {contract}").into())
+            }
+            ReceiptStatus::GasLimitTooHigh { gas_limit, max_gas } => {
+                Err(format!("Gas limit {gas_limit} exceeds the maximum of {max_gas}").into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+        *self = bincode::deserialize(snapshot)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContractOperation, ContractState, ExecutionError, Instruction::*, ReceiptStatus};
+    use crate::{generate_key_pair, to_account_id, StateMachine, Transaction};
+
+    #[test]
+    fn deploy_and_call() {
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let mut state = ContractState::default();
+
+        // Adds the argument to a counter and returns the new value
+        let code = vec![
+            Push(0),
+            Push(0),
+            Load,
+            Arg(0),
+            Add,
+            Store,
+            Push(0),
+            Load,
+            Return,
+        ];
+        let deploy = Transaction::new_with_cost(
+            account,
+            ContractOperation::Deploy {
+                code,
+                gas_limit: 1000,
+            },
+            1000,
+            skey.clone(),
+        );
+
+        let receipt = state.execute(&deploy).clone();
+        assert_eq!(receipt.status, ReceiptStatus::Deployed { contract: 0 });
+
+        for (arg, expected) in [(2, 2), (3, 5)] {
+            let call = Transaction::new_with_cost(
+                account,
+                ContractOperation::Call {
+                    contract: 0,
+                    args: vec![arg],
+                    gas_limit: 1000,
+                },
+                1000,
+                skey.clone(),
+            );
+
+            state.apply(&call).unwrap();
+            assert_eq!(
+                state.get_receipt(&call.get_id()).unwrap().status,
+                ReceiptStatus::Returned {
+                    value: Some(expected)
+                }
+            );
+        }
+
+        // Executing the same transaction again must not change the counter
+        let call = Transaction::new_with_cost(
+            account,
+            ContractOperation::Call {
+                contract: 0,
+                args: vec![4],
+                gas_limit: 1000,
+            },
+            1000,
+            skey.clone(),
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                state.execute(&call).status,
+                ReceiptStatus::Returned { value: Some(9) }
+            );
+        }
+
+        let out_of_gas = Transaction::new_with_cost(
+            account,
+            ContractOperation::Call {
+                contract: 0,
+                args: vec![1],
+                gas_limit: 10,
+            },
+            10,
+            skey,
+        );
+        assert!(state.apply(&out_of_gas).is_err());
+        assert!(!state
+            .get_receipt(&out_of_gas.get_id())
+            .unwrap()
+            .is_success());
+
+        assert_eq!(state.get_contract(&0).unwrap().storage.get(&0), Some(&9));
+
+        let mut restored = ContractState::default();
+        restored.restore(&state.snapshot()).unwrap();
+        assert_eq!(restored.digest(), state.digest());
+    }
+
+    #[test]
+    fn gas_limits() {
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let mut state = ContractState::default();
+
+        let deploy = Transaction::new_with_cost(
+            account,
+            ContractOperation::Deploy {
+                code: vec![Jump(0)],
+                gas_limit: 10,
+            },
+            10,
+            skey.clone(),
+        );
+        state.apply(&deploy).unwrap();
+
+        // The loop never ends, so execution has to stop once the gas limit is reached
+        let call = Transaction::new_with_cost(
+            account,
+            ContractOperation::Call {
+                contract: 0,
+                args: vec![],
+                gas_limit: 1000,
+            },
+            1000,
+            skey.clone(),
+        );
+        let receipt = state.execute(&call);
+        assert_eq!(
+            receipt.status,
+            ReceiptStatus::Failed {
+                error: ExecutionError::OutOfGas
+            }
+        );
+        assert_eq!(receipt.gas_used, 1000);
+
+        // The declared cost does not cover the gas limit
+        let call = Transaction::new_with_cost(
+            account,
+            ContractOperation::Call {
+                contract: 0,
+                args: vec![],
+                gas_limit: u64::MAX,
+            },
+            1000,
+            skey,
+        );
+        assert!(state.apply(&call).is_err());
+        let receipt = state.get_receipt(&call.get_id()).unwrap();
+        assert_eq!(
+            receipt.status,
+            ReceiptStatus::GasLimitTooHigh {
+                gas_limit: u64::MAX,
+                max_gas: 1000
+            }
+        );
+        assert_eq!(receipt.gas_used, 0);
+    }
+}
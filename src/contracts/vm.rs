@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+pub type Word = i64;
+pub type Storage = BTreeMap<Word, Word>;
+
+/// Maximum number of values on the stack
+const MAX_STACK_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Push(Word),
+    Pop,
+    Dup,
+    Swap,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Lt,
+    Gt,
+    Not,
+    /// Continue execution at the given instruction
+    Jump(usize),
+    /// Pops a value and jumps if it is not zero
+    JumpIf(usize),
+    /// Pushes the call argument with the given index
+    Arg(usize),
+    /// Pushes the (truncated) account id of the caller
+    Caller,
+    /// Pops a key and pushes the stored value (or zero)
+    Load,
+    /// Pops a value and a key and stores the value
+    Store,
+    /// Pops a value and appends it to the logs
+    Log,
+    /// Pops a value and stops execution successfully
+    Return,
+    /// Stops execution and discards all changes
+    Revert,
+    /// Stops execution successfully without a return value
+    Halt,
+}
+
+impl Instruction {
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Self::Load => 5,
+            Self::Store => 20,
+            Self::Log => 5,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionError {
+    OutOfGas,
+    Reverted,
+    StackUnderflow,
+    StackOverflow,
+    InvalidJump(usize),
+    InvalidArgument(usize),
+    DivisionByZero,
+    /// The result of a division does not fit into a word (`Word::MIN / -1`)
+    Overflow,
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfGas => write!(f, "Out of gas"),
+            Self::Reverted => write!(f, "Execution reverted"),
+            Self::StackUnderflow => write!(f, "Stack underflow"),
+            Self::StackOverflow => write!(f, "Stack overflow"),
+            Self::InvalidJump(pos) => write!(f, "Invalid jump target: {pos}"),
+            Self::InvalidArgument(idx) => write!(f, "No such argument: {idx}"),
+            Self::DivisionByZero => write!(f, "Division by zero"),
+            Self::Overflow => write!(f, "Arithmetic overflow"),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// Result of running a program
+#[derive(Clone, Debug)]
+pub struct Execution {
+    pub result: Result<Option<Word>, ExecutionError>,
+    pub gas_used: u64,
+    pub logs: Vec<Word>,
+}
+
+struct Machine<'a> {
+    code: &'a [Instruction],
+    args: &'a [Word],
+    caller: Word,
+    storage: &'a mut Storage,
+    stack: Vec<Word>,
+    logs: Vec<Word>,
+    gas_used: u64,
+    gas_limit: u64,
+}
+
+/// Runs a program until it stops, fails, or runs out of gas
+///
+/// Changes to `storage` are only kept if execution succeeds.
+pub fn execute(
+    code: &[Instruction],
+    args: &[Word],
+    caller: Word,
+    storage: &mut Storage,
+    gas_limit: u64,
+) -> Execution {
+    let mut scratch = storage.clone();
+
+    let mut machine = Machine {
+        code,
+        args,
+        caller,
+        storage: &mut scratch,
+        stack: Vec::new(),
+        logs: Vec::new(),
+        gas_used: 0,
+        gas_limit,
+    };
+
+    let result = machine.run();
+    let gas_used = machine.gas_used;
+    let mut logs = machine.logs;
+
+    // Like storage changes, logs of failed executions are discarded
+    if result.is_ok() {
+        *storage = scratch;
+    } else {
+        logs.clear();
+    }
+
+    Execution {
+        result,
+        gas_used,
+        logs,
+    }
+}
+
+impl Machine<'_> {
+    fn run(&mut self) -> Result<Option<Word>, ExecutionError> {
+        let mut pc = 0;
+
+        // Running past the end of the program is the same as halting
+        while let Some(instruction) = self.code.get(pc) {
+            self.gas_used += instruction.gas_cost();
+            if self.gas_used > self.gas_limit {
+                self.gas_used = self.gas_limit;
+                return Err(ExecutionError::OutOfGas);
+            }
+
+            pc += 1;
+
+            match instruction {
+                Instruction::Push(value) => self.push(*value)?,
+                Instruction::Pop => {
+                    self.pop()?;
+                }
+                Instruction::Dup => {
+                    let value = self.pop()?;
+                    self.push(value)?;
+                    self.push(value)?;
+                }
+                Instruction::Swap => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+                    self.push(a)?;
+                    self.push(b)?;
+                }
+                Instruction::Add => self.binary_op(|a, b| Ok(a.wrapping_add(b)))?,
+                Instruction::Sub => self.binary_op(|a, b| Ok(a.wrapping_sub(b)))?,
+                Instruction::Mul => self.binary_op(|a, b| Ok(a.wrapping_mul(b)))?,
+                Instruction::Div => self.binary_op(|a, b| divide(a, b, Word::checked_div))?,
+                Instruction::Mod => self.binary_op(|a, b| divide(a, b, Word::checked_rem))?,
+                Instruction::Eq => self.binary_op(|a, b| Ok((a == b) as Word))?,
+                Instruction::Lt => self.binary_op(|a, b| Ok((a < b) as Word))?,
+                Instruction::Gt => self.binary_op(|a, b| Ok((a > b) as Word))?,
+                Instruction::Not => {
+                    let value = self.pop()?;
+                    self.push((value == 0) as Word)?;
+                }
+                Instruction::Jump(target) => pc = self.check_jump(*target)?,
+                Instruction::JumpIf(target) => {
+                    if self.pop()? != 0 {
+                        pc = self.check_jump(*target)?;
+                    }
+                }
+                Instruction::Arg(index) => {
+                    let value = *self
+                        .args
+                        .get(*index)
+                        .ok_or(ExecutionError::InvalidArgument(*index))?;
+                    self.push(value)?;
+                }
+                Instruction::Caller => self.push(self.caller)?,
+                Instruction::Load => {
+                    let key = self.pop()?;
+                    let value = self.storage.get(&key).copied().unwrap_or(0);
+                    self.push(value)?;
+                }
+                Instruction::Store => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.storage.insert(key, value);
+                }
+                Instruction::Log => {
+                    let value = self.pop()?;
+                    self.logs.push(value);
+                }
+                Instruction::Return => return Ok(Some(self.pop()?)),
+                Instruction::Revert => return Err(ExecutionError::Reverted),
+                Instruction::Halt => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn push(&mut self, value: Word) -> Result<(), ExecutionError> {
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(ExecutionError::StackOverflow);
+        }
+
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Word, ExecutionError> {
+        self.stack.pop().ok_or(ExecutionError::StackUnderflow)
+    }
+
+    /// Pops `b` and then `a` and pushes `f(a, b)`
+    fn binary_op(
+        &mut self,
+        f: impl FnOnce(Word, Word) -> Result<Word, ExecutionError>,
+    ) -> Result<(), ExecutionError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(f(a, b)?)
+    }
+
+    fn check_jump(&self, target: usize) -> Result<usize, ExecutionError> {
+        if target < self.code.len() {
+            Ok(target)
+        } else {
+            Err(ExecutionError::InvalidJump(target))
+        }
+    }
+}
+
+/// Applies a checked division, distinguishing a zero divisor from an overflow
+fn divide(
+    a: Word,
+    b: Word,
+    f: impl FnOnce(Word, Word) -> Option<Word>,
+) -> Result<Word, ExecutionError> {
+    if b == 0 {
+        return Err(ExecutionError::DivisionByZero);
+    }
+
+    f(a, b).ok_or(ExecutionError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, ExecutionError, Instruction::*, Storage, Word};
+
+    #[test]
+    fn counter() {
+        // storage[0] += args[0]; log(storage[0]); return storage[0]
+        let code = vec![
+            Push(0),
+            Push(0),
+            Load,
+            Arg(0),
+            Add,
+            Store,
+            Push(0),
+            Load,
+            Dup,
+            Log,
+            Return,
+        ];
+
+        let mut storage = Storage::new();
+
+        let exec = execute(&code, &[5], 0, &mut storage, 1000);
+        assert_eq!(exec.result, Ok(Some(5)));
+        assert_eq!(exec.logs, vec![5]);
+
+        let exec = execute(&code, &[3], 0, &mut storage, 1000);
+        assert_eq!(exec.result, Ok(Some(8)));
+        assert_eq!(storage.get(&0), Some(&8));
+        assert_eq!(exec.gas_used, 42);
+    }
+
+    #[test]
+    fn failures_discard_changes() {
+        let mut storage = Storage::new();
+
+        // Stores a value and then loops forever
+        let code = vec![Push(1), Push(1), Store, Jump(3)];
+        let exec = execute(&code, &[], 0, &mut storage, 100);
+        assert_eq!(exec.result, Err(ExecutionError::OutOfGas));
+        assert_eq!(exec.gas_used, 100);
+
+        let code = vec![Push(1), Push(1), Store, Revert];
+        let exec = execute(&code, &[], 0, &mut storage, 100);
+        assert_eq!(exec.result, Err(ExecutionError::Reverted));
+
+        let code = vec![Push(1), Push(0), Div];
+        let exec = execute(&code, &[], 0, &mut storage, 100);
+        assert_eq!(exec.result, Err(ExecutionError::DivisionByZero));
+
+        let code = vec![Push(1), Push(0), Mod];
+        let exec = execute(&code, &[], 0, &mut storage, 100);
+        assert_eq!(exec.result, Err(ExecutionError::DivisionByZero));
+
+        for op in [Div, Mod] {
+            let code = vec![Push(Word::MIN), Push(-1), op];
+            let exec = execute(&code, &[], 0, &mut storage, 100);
+            assert_eq!(exec.result, Err(ExecutionError::Overflow));
+        }
+
+        assert!(storage.is_empty());
+    }
+}
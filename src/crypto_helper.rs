@@ -1,9 +1,12 @@
+use rsa::pss::{Signature, SigningKey, VerifyingKey};
+use rsa::signature::{RandomizedSigner, SignatureEncoding, Verifier};
 use rsa::traits::PublicKeyParts;
 
 use rand::rngs::OsRng;
 
+use bytes::Bytes;
 use digest::Digest;
-use sha2::Sha512;
+use sha2::{Sha256, Sha512};
 
 pub type PublicKey = rsa::RsaPublicKey;
 pub type PrivateKey = rsa::RsaPrivateKey;
@@ -29,11 +32,30 @@ pub fn to_account_id(key: &PublicKey) -> AccountId {
 
     let output = hasher.finalize();
     let mut buffer: [u8; 8] = [0; 8];
+    buffer.copy_from_slice(&output[..8]);
 
-    //FIXME get rid of this
-    for i in 0..8 {
-        buffer[i] = output[i];
-    }
+    u64::from_ne_bytes(buffer)
+}
+
+/// Signs the SHA-512 hash of the given data
+pub fn sign(private_key: &PrivateKey, data: &[u8]) -> Bytes {
+    let hash = Sha512::digest(data);
+
+    let mut rng = rand::thread_rng();
+    let signing_key = SigningKey::<Sha256>::new(private_key.clone());
+    let sig = signing_key.sign_with_rng(&mut rng, &hash);
+
+    sig.to_vec().into()
+}
+
+/// Checks a signature created by `sign`
+pub fn verify(public_key: &PublicKey, data: &[u8], signature: &[u8]) -> bool {
+    let hash = Sha512::digest(data);
+
+    let Ok(signature) = Signature::try_from(signature) else {
+        return false;
+    };
 
-    unsafe { std::mem::transmute::<[u8; 8], u64>(buffer) }
+    let verifying_key = VerifyingKey::<Sha256>::new(public_key.clone());
+    verifying_key.verify(&hash, &signature).is_ok()
 }
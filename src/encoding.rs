@@ -0,0 +1,269 @@
+//! Wire encodings supported by the simulator
+//!
+//! Every connection starts with a handshake: the client sends a single
+//! line of ASCII text naming the encoding (e.g. `json\n`).
+//! All following messages use that encoding.
+//! Bincode and CBOR messages are framed with a 4-byte big-endian length prefix,
+//! while JSON messages are sent one per line.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    Cbor,
+    Json,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bincode => "bincode",
+            Self::Cbor => "cbor",
+            Self::Json => "json",
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Bincode => {
+                bincode::serialize(value).map_err(|err| Error::Serialization(err.to_string()))
+            }
+            Self::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data)
+                    .map_err(|err| Error::Serialization(err.to_string()))?;
+                Ok(data)
+            }
+            Self::Json => {
+                serde_json::to_vec(value).map_err(|err| Error::Serialization(err.to_string()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+        match self {
+            Self::Bincode => {
+                bincode::deserialize(data).map_err(|err| Error::Deserialization(err.to_string()))
+            }
+            Self::Cbor => {
+                ciborium::from_reader(data).map_err(|err| Error::Deserialization(err.to_string()))
+            }
+            Self::Json => {
+                serde_json::from_slice(data).map_err(|err| Error::Deserialization(err.to_string()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.trim() {
+            "bincode" => Ok(Self::Bincode),
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            other => Err(Error::UnknownEncoding(other.to_string())),
+        }
+    }
+}
+
+/// Announces the encoding to the server
+#[cfg(feature = "tokio")]
+pub async fn write_handshake<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    encoding: Encoding,
+) -> Result<(), Error> {
+    use tokio::io::AsyncWriteExt;
+
+    let line = format!("{}\n", encoding.name());
+    writer.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Reads the encoding a client picked during connection setup
+#[cfg(feature = "tokio")]
+pub async fn read_handshake<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Encoding, Error> {
+    use tokio::io::AsyncReadExt;
+
+    /// Maximum length of the handshake line (excluding the newline)
+    const MAX_HANDSHAKE_LENGTH: usize = 32;
+
+    // Read byte-by-byte so we do not consume any data that belongs to the first message
+    let mut line = Vec::new();
+
+    loop {
+        let byte = reader.read_u8().await?;
+
+        if byte == b'\n' {
+            break;
+        }
+
+        if line.len() >= MAX_HANDSHAKE_LENGTH {
+            return Err(Error::UnknownEncoding(
+                String::from_utf8_lossy(&line).to_string(),
+            ));
+        }
+
+        line.push(byte);
+    }
+
+    let line = std::str::from_utf8(&line).map_err(|err| Error::UnknownEncoding(err.to_string()))?;
+    line.parse()
+}
+
+#[cfg(feature = "tokio-util")]
+mod codec {
+    use std::io;
+
+    use bytes::{Bytes, BytesMut};
+
+    use tokio_util::codec::length_delimited::LengthDelimitedCodec;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::Encoding;
+
+    /// Upper bound for a single JSON line (same as the length-delimited default)
+    const MAX_LINE_LENGTH: usize = 8 * 1024 * 1024;
+
+    /// Frames messages according to the negotiated encoding
+    pub enum WireCodec {
+        LengthDelimited(LengthDelimitedCodec),
+        Lines { next_index: usize },
+    }
+
+    impl WireCodec {
+        pub fn new(encoding: Encoding) -> Self {
+            match encoding {
+                Encoding::Bincode | Encoding::Cbor => {
+                    Self::LengthDelimited(LengthDelimitedCodec::new())
+                }
+                Encoding::Json => Self::Lines { next_index: 0 },
+            }
+        }
+    }
+
+    impl Decoder for WireCodec {
+        type Item = BytesMut;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+            match self {
+                Self::LengthDelimited(codec) => codec.decode(src),
+                Self::Lines { next_index } => {
+                    // Do not scan the same bytes twice
+                    let offset = src[*next_index..].iter().position(|b| *b == b'\n');
+
+                    let Some(offset) = offset else {
+                        if src.len() > MAX_LINE_LENGTH {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "JSON message too long",
+                            ));
+                        }
+
+                        *next_index = src.len();
+                        return Ok(None);
+                    };
+
+                    let end = *next_index + offset;
+                    *next_index = 0;
+
+                    let mut line = src.split_to(end + 1);
+                    line.truncate(end);
+
+                    if line.last() == Some(&b'\r') {
+                        line.truncate(end - 1);
+                    }
+
+                    Ok(Some(line))
+                }
+            }
+        }
+    }
+
+    impl Encoder<Bytes> for WireCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> Result<(), io::Error> {
+            match self {
+                Self::LengthDelimited(codec) => codec.encode(data, dst),
+                Self::Lines { .. } => {
+                    dst.reserve(data.len() + 1);
+                    dst.extend_from_slice(&data);
+                    dst.extend_from_slice(b"\n");
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio-util")]
+pub use codec::WireCodec;
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+    use crate::protocol::Message;
+    use crate::TestOperation;
+
+    #[test]
+    fn roundtrip() {
+        let msg = Message::<TestOperation>::NewEpochStarted {
+            sequence: 3,
+            identifier: 5,
+            timestamp: 1000,
+        };
+
+        for encoding in [Encoding::Bincode, Encoding::Cbor, Encoding::Json] {
+            let data = encoding.encode(&msg).unwrap();
+            let result: Message<TestOperation> = encoding.decode(&data).unwrap();
+
+            assert!(matches!(
+                result,
+                Message::NewEpochStarted {
+                    sequence: 3,
+                    identifier: 5,
+                    timestamp: 1000
+                }
+            ));
+
+            assert_eq!(encoding.name().parse::<Encoding>().unwrap(), encoding);
+        }
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[test]
+    fn json_lines() {
+        use super::WireCodec;
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        let mut codec = WireCodec::new(Encoding::Json);
+        let mut buf = BytesMut::from(&b"{\"a\":1}\r\n{\"b\""[..]);
+
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"{\"a\":1}");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b":2}\n");
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"{\"b\":2}");
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+
+use crate::protocol::{EpochId, RejectReason, SequenceNumber};
+
+#[derive(Debug)]
+pub enum Error {
+    // Tried to insert a transaction before the first epoch was created
+    NoEpoch,
+    EpochAlreadyExists(EpochId),
+    NoSuchEpoch(EpochId),
+    EpochSealed(EpochId),
+    // The statistics of a sealed epoch differ from the ones computed locally
+    InconsistentEpoch(EpochId),
+    Serialization(String),
+    Deserialization(String),
+    UnexpectedMessage(String),
+    OutOfOrder {
+        expected: SequenceNumber,
+        received: SequenceNumber,
+    },
+    UnknownEncoding(String),
+    AuthenticationFailed(String),
+    Tls(String),
+    // The server configuration is invalid or could not be loaded
+    InvalidConfig(String),
+    // The transaction was dropped instead of being committed
+    Rejected(RejectReason),
+    // Tried to add a signer to a transaction that is signed by its source alone
+    NotMultisig,
+    // The outbound queue of a peer overflowed
+    QueueFull,
+    PeerDisconnected,
+    ServerShutdown,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoEpoch => write!(
+                f,
+                "Cannot insert transaction before starting the first epoch"
+            ),
+            Self::EpochAlreadyExists(id) => write!(f, "Epoch {id} was created more than once"),
+            Self::NoSuchEpoch(id) => write!(f, "No such epoch: {id}"),
+            Self::EpochSealed(id) => write!(f, "Epoch {id} is already sealed"),
+            Self::InconsistentEpoch(id) => {
+                write!(
+                    f,
+                    "Epoch {id} does not match the statistics it was sealed with"
+                )
+            }
+            Self::Serialization(msg) => write!(f, "Failed to serialize message: {msg}"),
+            Self::Deserialization(msg) => write!(f, "Failed to deserialize message: {msg}"),
+            Self::UnexpectedMessage(msg) => write!(f, "Got unexpected message: {msg}"),
+            Self::OutOfOrder { expected, received } => write!(
+                f,
+                "Got message with sequence number {received} but expected {expected}"
+            ),
+            Self::UnknownEncoding(name) => write!(f, "Unknown encoding: {name}"),
+            Self::AuthenticationFailed(msg) => write!(f, "Authentication failed: {msg}"),
+            Self::Tls(msg) => write!(f, "TLS error: {msg}"),
+            Self::InvalidConfig(msg) => write!(f, "Invalid configuration: {msg}"),
+            Self::Rejected(reason) => write!(f, "Rejected transaction: {reason}"),
+            Self::NotMultisig => write!(f, "Not a multisig transaction"),
+            Self::QueueFull => write!(f, "Outbound queue is full"),
+            Self::PeerDisconnected => write!(f, "Peer disconnected"),
+            Self::ServerShutdown => write!(f, "Server is shutting down"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::EpochId;
+use crate::{to_account_id, AccountId, OpTrait, PublicKey, Transaction, TxPayload};
+
+/// Requires `threshold` of the `signers` to sign every transaction of an account
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub signers: Vec<AccountId>,
+    pub threshold: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdentityError {
+    AccountAlreadyExists(AccountId),
+    UnknownAccount(AccountId),
+    InvalidSignature,
+    InvalidPolicy(String),
+    ThresholdNotMet { required: usize, signed: usize },
+    Deactivated(AccountId),
+    InvalidSequence { expected: u64, received: u64 },
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AccountAlreadyExists(id) => write!(f, "Account {id} already exists"),
+            Self::UnknownAccount(id) => write!(f, "No such account: {id}"),
+            Self::InvalidSignature => write!(f, "Invalid signature"),
+            Self::InvalidPolicy(msg) => write!(f, "Invalid multisig policy: {msg}"),
+            Self::ThresholdNotMet { required, signed } => write!(
+                f,
+                "Transaction needs {required} signatures but only has {signed}"
+            ),
+            Self::Deactivated(id) => write!(f, "Account {id} was deactivated"),
+            Self::InvalidSequence { expected, received } => write!(
+                f,
+                "Key rotation has sequence number {received} but expected {expected}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+/// How transactions of an account are authorized at some point in time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Credentials {
+    pub public_key: PublicKey,
+    pub policy: Option<MultisigPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Identity {
+    /// All credentials the account ever had and the epoch from which on they were used
+    history: Vec<(EpochId, Credentials)>,
+    key_rotations: u64,
+    deactivated_at: Option<EpochId>,
+}
+
+impl Identity {
+    pub fn get_credentials(&self) -> &Credentials {
+        &self.history.last().unwrap().1
+    }
+
+    pub fn get_public_key(&self) -> &PublicKey {
+        &self.get_credentials().public_key
+    }
+
+    pub fn get_policy(&self) -> Option<&MultisigPolicy> {
+        self.get_credentials().policy.as_ref()
+    }
+
+    pub fn get_history(&self) -> &[(EpochId, Credentials)] {
+        &self.history
+    }
+
+    /// How often the key of the account was replaced so far
+    pub fn get_key_rotations(&self) -> u64 {
+        self.key_rotations
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.deactivated_at.is_none()
+    }
+
+    /// All credentials that were in use at some point during the given epoch
+    ///
+    /// There can be more than one, because the credentials might have changed during the epoch.
+    pub fn credentials_during(&self, epoch: EpochId) -> impl Iterator<Item = &Credentials> {
+        let deactivated = self.deactivated_at.is_some_and(|e| e < epoch);
+
+        self.history
+            .iter()
+            .enumerate()
+            .filter(move |(pos, (valid_from, _))| {
+                let replaced_before = self
+                    .history
+                    .get(pos + 1)
+                    .is_some_and(|(next, _)| *next < epoch);
+
+                !deactivated && *valid_from <= epoch && !replaced_before
+            })
+            .map(|(_, (_, credentials))| credentials)
+    }
+}
+
+/// Keeps track of all accounts created using `TxPayload::CreateAccount` and their credentials
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IdentityRegistry {
+    accounts: BTreeMap<AccountId, Identity>,
+}
+
+impl IdentityRegistry {
+    pub fn get(&self, account: &AccountId) -> Option<&Identity> {
+        self.accounts.get(account)
+    }
+
+    pub fn contains(&self, account: &AccountId) -> bool {
+        self.accounts.contains_key(account)
+    }
+
+    /// Checks that the transaction was issued by its source account
+    ///
+    /// Accounts with a multisig policy need enough signatures of their signers;
+    /// all other accounts need a signature of their own key.
+    pub fn authorize<OpType: OpTrait>(
+        &self,
+        tx: &Transaction<OpType>,
+    ) -> Result<(), IdentityError> {
+        if let Some(result) = Self::authorize_creation(tx) {
+            return result;
+        }
+
+        let source = *tx.get_source();
+        let identity = self
+            .accounts
+            .get(&source)
+            .ok_or(IdentityError::UnknownAccount(source))?;
+
+        if !identity.is_active() {
+            return Err(IdentityError::Deactivated(source));
+        }
+
+        self.check_credentials(tx, [identity.get_credentials()], |signer| {
+            self.accounts
+                .get(signer)
+                .filter(|id| id.is_active())
+                .map(|id| id.get_public_key())
+                .into_iter()
+                .collect()
+        })
+    }
+
+    /// Like `authorize` but uses the credentials that were valid during the given epoch
+    ///
+    /// This allows verifying transactions that were signed with a key that has been rotated since.
+    pub fn authorize_at<OpType: OpTrait>(
+        &self,
+        tx: &Transaction<OpType>,
+        epoch: EpochId,
+    ) -> Result<(), IdentityError> {
+        if let Some(result) = Self::authorize_creation(tx) {
+            return result;
+        }
+
+        let source = *tx.get_source();
+        let identity = self
+            .accounts
+            .get(&source)
+            .ok_or(IdentityError::UnknownAccount(source))?;
+
+        self.check_credentials(tx, identity.credentials_during(epoch), |signer| {
+            self.accounts
+                .get(signer)
+                .map(|id| {
+                    id.credentials_during(epoch)
+                        .map(|c| &c.public_key)
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+    }
+
+    /// Account creation is always authorized by the key that is being registered
+    fn authorize_creation<OpType: OpTrait>(
+        tx: &Transaction<OpType>,
+    ) -> Option<Result<(), IdentityError>> {
+        let TxPayload::CreateAccount { public_key } = tx.get_payload() else {
+            return None;
+        };
+
+        if to_account_id(public_key) != *tx.get_source() || !tx.verify(public_key) {
+            Some(Err(IdentityError::InvalidSignature))
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    /// Succeeds if any of the given credentials authorize the transaction
+    fn check_credentials<'a, OpType: OpTrait>(
+        &'a self,
+        tx: &Transaction<OpType>,
+        candidates: impl IntoIterator<Item = &'a Credentials>,
+        signer_keys: impl Fn(&AccountId) -> Vec<&'a PublicKey>,
+    ) -> Result<(), IdentityError> {
+        // There are no candidates if the account did not exist (or was deactivated) at that time
+        let mut result = Err(IdentityError::UnknownAccount(*tx.get_source()));
+
+        for credentials in candidates {
+            result = match &credentials.policy {
+                Some(policy) => {
+                    let signed = policy
+                        .signers
+                        .iter()
+                        .filter(|signer| {
+                            signer_keys(signer)
+                                .into_iter()
+                                .any(|key| tx.verify_signer(signer, key))
+                        })
+                        .count();
+
+                    if signed >= policy.threshold {
+                        Ok(())
+                    } else {
+                        Err(IdentityError::ThresholdNotMet {
+                            required: policy.threshold,
+                            signed,
+                        })
+                    }
+                }
+                None => {
+                    if tx.verify(&credentials.public_key) {
+                        Ok(())
+                    } else {
+                        Err(IdentityError::InvalidSignature)
+                    }
+                }
+            };
+
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// Checks whether `apply` would succeed, without changing the registry
+    ///
+    /// Transactions that carry an operation always succeed here; use `authorize` for them.
+    pub fn check<OpType: OpTrait>(&self, tx: &Transaction<OpType>) -> Result<(), IdentityError> {
+        let source = *tx.get_source();
+
+        if let TxPayload::Operation { .. } = tx.get_payload() {
+            return Ok(());
+        }
+
+        self.authorize(tx)?;
+
+        match tx.get_payload() {
+            TxPayload::CreateAccount { .. } => {
+                // Deactivated accounts keep their identity, so they cannot be created again
+                if let Some(identity) = self.accounts.get(&source) {
+                    return if identity.is_active() {
+                        Err(IdentityError::AccountAlreadyExists(source))
+                    } else {
+                        Err(IdentityError::Deactivated(source))
+                    };
+                }
+            }
+            TxPayload::SetMultisigPolicy { policy } => self.check_policy(policy)?,
+            TxPayload::RotateKey { sequence, .. } => {
+                let expected = self.accounts[&source].key_rotations;
+
+                if *sequence != expected {
+                    return Err(IdentityError::InvalidSequence {
+                        expected,
+                        received: *sequence,
+                    });
+                }
+            }
+            TxPayload::DeactivateAccount | TxPayload::Operation { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// Updates the registry if the transaction creates an account or changes its credentials
+    ///
+    /// `epoch` is the epoch the transaction is part of. Unauthorized or invalid changes
+    /// leave the registry untouched.
+    pub fn apply<OpType: OpTrait>(
+        &mut self,
+        tx: &Transaction<OpType>,
+        epoch: EpochId,
+    ) -> Result<(), IdentityError> {
+        self.check(tx)?;
+
+        let source = *tx.get_source();
+
+        let credentials = match tx.get_payload() {
+            TxPayload::CreateAccount { public_key } => {
+                let credentials = Credentials {
+                    public_key: public_key.clone(),
+                    policy: None,
+                };
+
+                self.accounts.insert(
+                    source,
+                    Identity {
+                        history: vec![(epoch, credentials)],
+                        key_rotations: 0,
+                        deactivated_at: None,
+                    },
+                );
+
+                return Ok(());
+            }
+            TxPayload::SetMultisigPolicy { policy } => Credentials {
+                policy: Some(policy.clone()),
+                ..self.accounts[&source].get_credentials().clone()
+            },
+            TxPayload::RotateKey { new_public_key, .. } => {
+                let identity = self.accounts.get_mut(&source).unwrap();
+                identity.key_rotations += 1;
+
+                Credentials {
+                    public_key: new_public_key.clone(),
+                    ..identity.get_credentials().clone()
+                }
+            }
+            TxPayload::DeactivateAccount => {
+                self.accounts.get_mut(&source).unwrap().deactivated_at = Some(epoch);
+                return Ok(());
+            }
+            TxPayload::Operation { .. } => return Ok(()),
+        };
+
+        let identity = self.accounts.get_mut(&source).unwrap();
+        identity.history.push((epoch, credentials));
+
+        Ok(())
+    }
+
+    fn check_policy(&self, policy: &MultisigPolicy) -> Result<(), IdentityError> {
+        let signers: BTreeSet<_> = policy.signers.iter().collect();
+
+        if signers.len() != policy.signers.len() {
+            return Err(IdentityError::InvalidPolicy(
+                "Signers must be distinct".to_string(),
+            ));
+        }
+
+        if policy.threshold == 0 || policy.threshold > signers.len() {
+            return Err(IdentityError::InvalidPolicy(format!(
+                "Threshold must be between 1 and {}",
+                signers.len()
+            )));
+        }
+
+        if let Some(signer) = signers
+            .iter()
+            .find(|s| !self.accounts.get(s).is_some_and(|id| id.is_active()))
+        {
+            return Err(IdentityError::UnknownAccount(**signer));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdentityError, IdentityRegistry, MultisigPolicy};
+    use crate::{
+        generate_key_pair, to_account_id, Error, TestOperation, Transaction, TxPayload, Validity,
+    };
+
+    #[test]
+    fn threshold() {
+        let mut registry = IdentityRegistry::default();
+
+        let keys: Vec<_> = (0..3)
+            .map(|_| {
+                let (skey, pkey) = generate_key_pair();
+                let account = to_account_id(&pkey);
+
+                let tx = Transaction::<TestOperation>::new_create_account(pkey, skey.clone());
+                registry.apply(&tx, 0).unwrap();
+
+                (account, skey)
+            })
+            .collect();
+
+        let (escrow, escrow_key) = keys[0].clone();
+        let (alice, alice_key) = keys[1].clone();
+        let (bob, bob_key) = keys[2].clone();
+
+        let invalid = MultisigPolicy {
+            signers: vec![alice, bob],
+            threshold: 3,
+        };
+        let tx = Transaction::<TestOperation>::new_set_multisig_policy(
+            escrow,
+            invalid,
+            escrow_key.clone(),
+        );
+        assert!(matches!(
+            registry.apply(&tx, 0),
+            Err(IdentityError::InvalidPolicy(_))
+        ));
+
+        let policy = MultisigPolicy {
+            signers: vec![alice, bob],
+            threshold: 2,
+        };
+        let tx = Transaction::<TestOperation>::new_set_multisig_policy(
+            escrow,
+            policy,
+            escrow_key.clone(),
+        );
+        registry.apply(&tx, 0).unwrap();
+
+        // The key of the account itself is no longer sufficient
+        let mut tx = Transaction::new(escrow, TestOperation::Empty {}, escrow_key);
+        assert!(matches!(
+            tx.add_signature(alice, &alice_key),
+            Err(Error::NotMultisig)
+        ));
+        assert_eq!(
+            registry.authorize(&tx),
+            Err(IdentityError::ThresholdNotMet {
+                required: 2,
+                signed: 0
+            })
+        );
+
+        let payload = TxPayload::Operation {
+            operation: TestOperation::Empty {},
+        };
+        let mut tx = Transaction::new_multisig(escrow, payload, 1, Validity::default());
+        tx.add_signature(alice, &alice_key).unwrap();
+
+        // Signing twice does not count twice
+        tx.add_signature(alice, &alice_key).unwrap();
+        assert_eq!(
+            registry.authorize(&tx),
+            Err(IdentityError::ThresholdNotMet {
+                required: 2,
+                signed: 1
+            })
+        );
+
+        // Bob's key is not valid for Alice
+        let mut forged = tx.clone();
+        forged.add_signature(bob, &alice_key).unwrap();
+        assert!(registry.authorize(&forged).is_err());
+
+        tx.add_signature(bob, &bob_key).unwrap();
+        assert_eq!(registry.authorize(&tx), Ok(()));
+    }
+
+    #[test]
+    fn rotation_and_deactivation() {
+        let mut registry = IdentityRegistry::default();
+
+        let (first_skey, first_pkey) = generate_key_pair();
+        let (second_skey, second_pkey) = generate_key_pair();
+        let account = to_account_id(&first_pkey);
+
+        let create = Transaction::<TestOperation>::new_create_account(
+            first_pkey.clone(),
+            first_skey.clone(),
+        );
+        registry.apply(&create, 0).unwrap();
+
+        let rotate = Transaction::<TestOperation>::new_rotate_key(
+            account,
+            second_pkey,
+            0,
+            first_skey.clone(),
+        );
+        registry.apply(&rotate, 0).unwrap();
+
+        let rotate_back =
+            Transaction::<TestOperation>::new_rotate_key(account, first_pkey, 1, second_skey);
+        registry.apply(&rotate_back, 1).unwrap();
+        assert_eq!(registry.get(&account).unwrap().get_key_rotations(), 2);
+
+        // The first rotation is signed by the current key again, but cannot be replayed
+        assert_eq!(
+            registry.apply(&rotate, 2),
+            Err(IdentityError::InvalidSequence {
+                expected: 2,
+                received: 0
+            })
+        );
+
+        let deactivate = Transaction::<TestOperation>::new_deactivate_account(account, first_skey);
+        registry.apply(&deactivate, 2).unwrap();
+
+        // A deactivated account cannot be created again
+        assert_eq!(
+            registry.apply(&create, 3),
+            Err(IdentityError::Deactivated(account))
+        );
+        assert!(!registry.get(&account).unwrap().is_active());
+    }
+}
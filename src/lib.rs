@@ -1,36 +1,67 @@
 #![feature(trait_alias)]
 
+pub mod encoding;
 pub mod protocol;
 use protocol::EpochId;
 
 mod transactions;
 pub use transactions::*;
 
-use std::collections::{BTreeMap, HashMap};
+mod error;
+pub use error::Error;
+
+mod mirror;
+pub use mirror::LedgerMirror;
+
+mod state_machine;
+pub use state_machine::{SharedStateMachine, StateError, StateMachine};
+
+mod identity;
+pub use identity::{Credentials, Identity, IdentityError, IdentityRegistry, MultisigPolicy};
+
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::sync::{Mutex, RwLock};
 
 #[cfg(feature = "server")]
 pub mod server;
 
+#[cfg(feature = "token")]
+pub mod token;
+
+#[cfg(feature = "contracts")]
+pub mod contracts;
+
+#[cfg(all(feature = "tokio", feature = "tokio-util", feature = "futures-util"))]
+pub mod workload;
+
 use serde::{Deserialize, Serialize};
 
 pub const DEFAULT_BLOCKCHAIN_PORT: u16 = 8080;
 
 mod crypto_helper;
-pub use crypto_helper::{generate_key_pair, to_account_id, AccountId, PrivateKey, PublicKey};
-
-pub trait OpTrait = Clone + Debug + Sync + Send + 'static;
-
-pub struct Identity {
-    #[allow(dead_code)]
-    public_key: PublicKey,
+pub use crypto_helper::{
+    generate_key_pair, sign, to_account_id, verify, AccountId, PrivateKey, PublicKey,
+};
+
+pub trait OpTrait = Serialize + Clone + Debug + Sync + Send + 'static;
+
+/// Aggregate statistics that are recorded when an epoch is sealed
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EpochStatistics {
+    pub end_timestamp: i64,
+    pub num_transactions: usize,
+    pub byte_size: u64,
+    pub total_cost: u64,
+    pub transactions_per_account: BTreeMap<AccountId, usize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Epoch<OpType: OpTrait> {
     timestamp: i64,
     transactions: Vec<Transaction<OpType>>,
+    /// Only set once the epoch is sealed
+    statistics: Option<EpochStatistics>,
 }
 
 impl<OpType: OpTrait> Epoch<OpType> {
@@ -38,7 +69,37 @@ impl<OpType: OpTrait> Epoch<OpType> {
         Self {
             timestamp,
             transactions: Vec::new(),
+            statistics: None,
+        }
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.statistics.is_some()
+    }
+
+    pub fn get_statistics(&self) -> Option<&EpochStatistics> {
+        self.statistics.as_ref()
+    }
+
+    /// Prevents further transactions from being added and records statistics about the epoch
+    fn seal(&mut self, end_timestamp: i64) -> EpochStatistics {
+        let mut transactions_per_account = BTreeMap::new();
+        for tx in &self.transactions {
+            *transactions_per_account
+                .entry(*tx.get_source())
+                .or_default() += 1;
         }
+
+        let statistics = EpochStatistics {
+            end_timestamp,
+            num_transactions: self.size(),
+            byte_size: self.byte_size(),
+            total_cost: self.total_cost(),
+            transactions_per_account,
+        };
+
+        self.statistics = Some(statistics.clone());
+        statistics
     }
 
     pub fn size(&self) -> usize {
@@ -52,19 +113,28 @@ impl<OpType: OpTrait> Epoch<OpType> {
     pub fn get_timestamp(&self) -> i64 {
         self.timestamp
     }
+
+    /// Sum of the declared cost of all transactions in this epoch
+    pub fn total_cost(&self) -> u64 {
+        self.transactions.iter().map(|tx| tx.get_cost()).sum()
+    }
+
+    /// Serialized size of all transactions in this epoch
+    pub fn byte_size(&self) -> u64 {
+        self.transactions.iter().map(|tx| tx.byte_size()).sum()
+    }
 }
 
 type EpochMap<OpType> = BTreeMap<EpochId, Mutex<Epoch<OpType>>>;
 
 pub struct Ledger<OpType: OpTrait> {
-    #[allow(dead_code)]
-    identities: Mutex<HashMap<AccountId, Identity>>,
+    identities: Mutex<IdentityRegistry>,
     epochs: RwLock<EpochMap<OpType>>,
 }
 
 impl<OpType: OpTrait> Default for Ledger<OpType> {
     fn default() -> Self {
-        let identities = Mutex::new(HashMap::default());
+        let identities = Mutex::new(IdentityRegistry::default());
         let epochs = RwLock::new(EpochMap::default());
 
         Self { identities, epochs }
@@ -72,40 +142,76 @@ impl<OpType: OpTrait> Default for Ledger<OpType> {
 }
 
 impl<OpType: OpTrait> Ledger<OpType> {
-    pub fn insert(&self, tx: Transaction<OpType>) {
-        /*   if tx.op_type == OpType::CreateAccount {
-            self.identity_mgr.create_account(&tx);
-        }*/
-
+    /// Appends a transaction to the current epoch
+    ///
+    /// This does not check whether the transaction is authorized (see `authorize`), but
+    /// only authorized transactions can create accounts or change their policies.
+    pub fn insert(&self, tx: Transaction<OpType>) -> Result<(), Error> {
         // Hold the lock to this throughout the entire modification to avoid race conditions
         let epochs = self.epochs.read().unwrap();
 
-        let epoch = match epochs.last_key_value() {
-            Some((_, v)) => v,
+        let (identifier, epoch) = match epochs.last_key_value() {
+            Some(entry) => entry,
             None => {
-                panic!("Cannot insert transaction before starting the first epoch!");
+                return Err(Error::NoEpoch);
             }
         };
 
         let mut lock = epoch.lock().unwrap();
+
+        if lock.is_sealed() {
+            return Err(Error::EpochSealed(*identifier));
+        }
+
+        // Invalid changes are part of the ledger but do not affect the registry
+        let _ = self.identities.lock().unwrap().apply(&tx, *identifier);
         lock.transactions.push(tx);
+
+        Ok(())
+    }
+
+    /// Checks the transaction against the current credentials of its source
+    pub fn authorize(&self, tx: &Transaction<OpType>) -> Result<(), IdentityError> {
+        self.identities.lock().unwrap().authorize(tx)
     }
 
-    pub fn get_epoch_timestamp(&self, identifier: EpochId) -> i64 {
+    /// Checks a transaction of the given epoch against the credentials valid at that time
+    pub fn authorize_at(
+        &self,
+        tx: &Transaction<OpType>,
+        epoch: EpochId,
+    ) -> Result<(), IdentityError> {
+        self.identities.lock().unwrap().authorize_at(tx, epoch)
+    }
+
+    pub fn has_account(&self, account: &AccountId) -> bool {
+        self.identities.lock().unwrap().contains(account)
+    }
+
+    /// Returns a copy of the identity of an account created in the ledger
+    pub fn get_identity(&self, account: &AccountId) -> Option<Identity> {
+        self.identities.lock().unwrap().get(account).cloned()
+    }
+
+    pub fn get_epoch_timestamp(&self, identifier: EpochId) -> Result<i64, Error> {
         let epochs = self.epochs.read().unwrap();
-        let epoch = epochs.get(&identifier).unwrap();
+        let epoch = epochs
+            .get(&identifier)
+            .ok_or(Error::NoSuchEpoch(identifier))?;
 
         let lock = epoch.lock().unwrap();
-        lock.get_timestamp()
+        Ok(lock.get_timestamp())
     }
 
     // Returns a copy of an epoch
-    pub fn get_epoch(&self, identifier: EpochId) -> Epoch<OpType> {
+    pub fn get_epoch(&self, identifier: EpochId) -> Result<Epoch<OpType>, Error> {
         let epochs = self.epochs.read().unwrap();
-        let epoch = epochs.get(&identifier).expect("No such epoch");
+        let epoch = epochs
+            .get(&identifier)
+            .ok_or(Error::NoSuchEpoch(identifier))?;
 
         let lock = epoch.lock().unwrap();
-        lock.clone()
+        Ok(lock.clone())
     }
 
     pub fn num_transactions(&self) -> usize {
@@ -125,13 +231,8 @@ impl<OpType: OpTrait> Ledger<OpType> {
         epochs.len()
     }
 
-    pub fn create_new_epoch(&self, identifier: EpochId, timestamp: i64) {
-        let mut epochs = self.epochs.write().unwrap();
-        let result = epochs.insert(identifier, Mutex::new(Epoch::new(timestamp)));
-
-        if result.is_some() {
-            panic!("Epoch {} was created more than once", identifier);
-        }
+    pub fn create_new_epoch(&self, identifier: EpochId, timestamp: i64) -> Result<(), Error> {
+        self.synchronize_epoch(identifier, Epoch::new(timestamp))
     }
 
     pub fn has_gaps(&self) -> bool {
@@ -148,13 +249,45 @@ impl<OpType: OpTrait> Ledger<OpType> {
         false
     }
 
-    pub fn synchronize_epoch(&self, identifier: EpochId, epoch: Epoch<OpType>) {
+    pub fn synchronize_epoch(
+        &self,
+        identifier: EpochId,
+        epoch: Epoch<OpType>,
+    ) -> Result<(), Error> {
         let mut epochs = self.epochs.write().unwrap();
-        let result = epochs.insert(identifier, Mutex::new(epoch));
 
-        if result.is_some() {
-            panic!("Epoch {} was created more than once", identifier);
+        // Do not overwrite an existing epoch
+        if epochs.contains_key(&identifier) {
+            return Err(Error::EpochAlreadyExists(identifier));
         }
+
+        let mut identities = self.identities.lock().unwrap();
+        for tx in epoch.get_transactions() {
+            let _ = identities.apply(tx, identifier);
+        }
+
+        epochs.insert(identifier, Mutex::new(epoch));
+        Ok(())
+    }
+
+    /// Seals an epoch so that no more transactions can be added to it
+    pub fn seal_epoch(
+        &self,
+        identifier: EpochId,
+        end_timestamp: i64,
+    ) -> Result<EpochStatistics, Error> {
+        let epochs = self.epochs.read().unwrap();
+        let epoch = epochs
+            .get(&identifier)
+            .ok_or(Error::NoSuchEpoch(identifier))?;
+
+        let mut lock = epoch.lock().unwrap();
+
+        if lock.is_sealed() {
+            return Err(Error::EpochSealed(identifier));
+        }
+
+        Ok(lock.seal(end_timestamp))
     }
 
     pub fn get_current_epoch(&self) -> EpochId {
@@ -172,7 +305,9 @@ pub enum TestOperation {
 
 #[cfg(test)]
 mod tests {
-    use crate::{generate_key_pair, to_account_id, Ledger, TestOperation, Transaction};
+    use crate::{
+        generate_key_pair, to_account_id, Error, IdentityError, Ledger, TestOperation, Transaction,
+    };
 
     #[test]
     fn size() {
@@ -184,23 +319,42 @@ mod tests {
         let account = to_account_id(&pkey);
 
         let tx = Transaction::new(account, TestOperation::Empty {}, skey);
-        ledger.create_new_epoch(0, 5);
-        ledger.insert(tx);
+        ledger.create_new_epoch(0, 5).unwrap();
+        ledger.insert(tx).unwrap();
 
-        let epoch = ledger.get_epoch(0);
+        let epoch = ledger.get_epoch(0).unwrap();
         assert_eq!(epoch.size(), 1);
     }
 
+    #[test]
+    fn invalid_epochs() {
+        let ledger = Ledger::default();
+
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let tx = Transaction::new(account, TestOperation::Empty {}, skey);
+        assert!(matches!(ledger.insert(tx), Err(Error::NoEpoch)));
+        assert!(matches!(ledger.get_epoch(0), Err(Error::NoSuchEpoch(0))));
+
+        ledger.create_new_epoch(0, 5).unwrap();
+        assert!(matches!(
+            ledger.create_new_epoch(0, 6),
+            Err(Error::EpochAlreadyExists(0))
+        ));
+        assert_eq!(ledger.get_epoch_timestamp(0).unwrap(), 5);
+    }
+
     #[test]
     fn has_gaps() {
         let ledger = Ledger::<TestOperation>::default();
-        ledger.create_new_epoch(1, 5);
+        ledger.create_new_epoch(1, 5).unwrap();
 
-        assert_eq!(true, ledger.has_gaps());
+        assert!(ledger.has_gaps());
 
-        ledger.create_new_epoch(0, 1);
+        ledger.create_new_epoch(0, 1).unwrap();
 
-        assert_eq!(false, ledger.has_gaps());
+        assert!(!ledger.has_gaps());
         assert_eq!(1, ledger.get_current_epoch());
     }
 
@@ -213,16 +367,108 @@ mod tests {
         let account = to_account_id(&pkey);
 
         let tx = Transaction::new(account, TestOperation::Empty {}, skey);
-        ledger.create_new_epoch(0, 5);
-        ledger.insert(tx);
+        ledger.create_new_epoch(0, 5).unwrap();
+        ledger.insert(tx).unwrap();
 
-        let epoch = ledger.get_epoch(0);
-        copy.synchronize_epoch(0, epoch);
+        let epoch = ledger.get_epoch(0).unwrap();
+        copy.synchronize_epoch(0, epoch).unwrap();
 
-        let ecopy = copy.get_epoch(0);
+        let ecopy = copy.get_epoch(0).unwrap();
 
         assert_eq!(copy.num_epochs(), 1);
         assert_eq!(ecopy.size(), 1);
         assert_eq!(copy.num_transactions(), 1);
     }
+
+    #[test]
+    fn seal() {
+        let ledger = Ledger::default();
+
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        ledger.create_new_epoch(0, 5).unwrap();
+        for _ in 0..2 {
+            let tx = Transaction::new_with_cost(account, TestOperation::Empty {}, 3, skey.clone());
+            ledger.insert(tx).unwrap();
+        }
+
+        let statistics = ledger.seal_epoch(0, 10).unwrap();
+        assert_eq!(statistics.end_timestamp, 10);
+        assert_eq!(statistics.num_transactions, 2);
+        assert_eq!(statistics.total_cost, 6);
+        assert_eq!(statistics.transactions_per_account.get(&account), Some(&2));
+
+        let epoch = ledger.get_epoch(0).unwrap();
+        assert_eq!(statistics.byte_size, epoch.byte_size());
+        assert_eq!(epoch.get_statistics(), Some(&statistics));
+
+        let tx = Transaction::new(account, TestOperation::Empty {}, skey);
+        assert!(matches!(ledger.insert(tx), Err(Error::EpochSealed(0))));
+        assert!(matches!(
+            ledger.seal_epoch(0, 11),
+            Err(Error::EpochSealed(0))
+        ));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let ledger = Ledger::default();
+
+        let (old_skey, old_pkey) = generate_key_pair();
+        let (new_skey, new_pkey) = generate_key_pair();
+        let account = to_account_id(&old_pkey);
+
+        let old_tx = Transaction::new(account, TestOperation::Empty {}, old_skey.clone());
+        let new_tx = Transaction::new(account, TestOperation::Empty {}, new_skey.clone());
+
+        ledger.create_new_epoch(0, 0).unwrap();
+        ledger
+            .insert(Transaction::new_create_account(old_pkey, old_skey.clone()))
+            .unwrap();
+        ledger.insert(old_tx.clone()).unwrap();
+        assert!(ledger.authorize(&old_tx).is_ok());
+
+        ledger.create_new_epoch(1, 1).unwrap();
+
+        // Rotating with the wrong key has no effect
+        ledger
+            .insert(Transaction::new_rotate_key(
+                account,
+                new_pkey.clone(),
+                0,
+                new_skey.clone(),
+            ))
+            .unwrap();
+        assert!(ledger.authorize(&new_tx).is_err());
+
+        ledger
+            .insert(Transaction::new_rotate_key(account, new_pkey, 0, old_skey))
+            .unwrap();
+        assert_eq!(
+            ledger.authorize(&old_tx),
+            Err(IdentityError::InvalidSignature)
+        );
+        assert!(ledger.authorize(&new_tx).is_ok());
+
+        ledger.create_new_epoch(2, 2).unwrap();
+        ledger
+            .insert(Transaction::new_deactivate_account(account, new_skey))
+            .unwrap();
+        assert_eq!(
+            ledger.authorize(&new_tx),
+            Err(IdentityError::Deactivated(account))
+        );
+
+        // Both keys were valid during the epoch in which the key was rotated
+        assert!(ledger.authorize_at(&old_tx, 0).is_ok());
+        assert!(ledger.authorize_at(&old_tx, 1).is_ok());
+        assert!(ledger.authorize_at(&new_tx, 1).is_ok());
+        assert!(ledger.authorize_at(&old_tx, 2).is_err());
+        assert!(ledger.authorize_at(&new_tx, 2).is_ok());
+        assert!(ledger.authorize_at(&new_tx, 3).is_err());
+
+        let identity = ledger.get_identity(&account).unwrap();
+        assert_eq!(identity.get_history().len(), 2);
+    }
 }
@@ -0,0 +1,243 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::protocol::{Message, SequenceNumber};
+use crate::state_machine::SharedStateMachine;
+use crate::{Error, Ledger, OpTrait, Transaction};
+
+/// Client-side copy of the ledger that is kept up to date using messages from the server
+pub struct LedgerMirror<OpType: OpTrait> {
+    ledger: Arc<Ledger<OpType>>,
+    last_sequence: Mutex<Option<SequenceNumber>>,
+    state_machine: Option<SharedStateMachine<OpType>>,
+    num_transactions: Mutex<usize>,
+    transactions_changed: Condvar,
+}
+
+impl<OpType: OpTrait> Default for LedgerMirror<OpType> {
+    fn default() -> Self {
+        Self {
+            ledger: Arc::new(Ledger::default()),
+            last_sequence: Mutex::new(None),
+            state_machine: None,
+            num_transactions: Mutex::new(0),
+            transactions_changed: Condvar::new(),
+        }
+    }
+}
+
+impl<OpType: OpTrait> LedgerMirror<OpType> {
+    /// Creates a mirror that also applies every transaction to the given state machine
+    ///
+    /// The state machine must be in its initial state.
+    pub fn with_state_machine(state_machine: SharedStateMachine<OpType>) -> Self {
+        Self {
+            state_machine: Some(state_machine),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_ledger(&self) -> &Arc<Ledger<OpType>> {
+        &self.ledger
+    }
+
+    /// Hash of the state derived from this ledger (if there is a state machine)
+    pub fn state_digest(&self) -> Option<Vec<u8>> {
+        self.state_machine
+            .as_ref()
+            .map(|sm| sm.lock().unwrap().digest())
+    }
+
+    /// Blocks until the mirror contains at least `count` transactions
+    ///
+    /// Returns false if that did not happen within the given timeout.
+    pub fn wait_for_transactions(&self, count: usize, timeout: Duration) -> bool {
+        let num_transactions = self.num_transactions.lock().unwrap();
+
+        let (_num_transactions, result) = self
+            .transactions_changed
+            .wait_timeout_while(num_transactions, timeout, |num| *num < count)
+            .unwrap();
+
+        !result.timed_out()
+    }
+
+    fn add_transactions(&self, count: usize) {
+        *self.num_transactions.lock().unwrap() += count;
+        self.transactions_changed.notify_all();
+    }
+
+    /// Applies a message received from the server
+    ///
+    /// Fails if the message would not leave the ledger in the same state as the server's
+    /// (e.g., because an update was skipped).
+    pub fn handle_message(&self, msg: Message<OpType>) -> Result<(), Error> {
+        match msg {
+            Message::SyncEpoch { identifier, epoch } => {
+                let count = epoch.size();
+
+                // Only replay epochs the ledger accepted, so the state cannot diverge from it
+                let replay = self
+                    .state_machine
+                    .as_ref()
+                    .map(|state_machine| (state_machine, epoch.clone()));
+
+                self.ledger.synchronize_epoch(identifier, epoch)?;
+
+                if let Some((state_machine, epoch)) = replay {
+                    state_machine.lock().unwrap().replay_epoch(&epoch);
+                }
+
+                self.add_transactions(count);
+                Ok(())
+            }
+            Message::NewEpochStarted {
+                sequence,
+                identifier,
+                timestamp,
+            } => {
+                self.check_sequence(sequence)?;
+                self.ledger.create_new_epoch(identifier, timestamp)
+            }
+            Message::EpochSealed {
+                sequence,
+                identifier,
+                statistics,
+            } => {
+                self.check_sequence(sequence)?;
+
+                let local = self
+                    .ledger
+                    .seal_epoch(identifier, statistics.end_timestamp)?;
+
+                if local != statistics {
+                    return Err(Error::InconsistentEpoch(identifier));
+                }
+
+                Ok(())
+            }
+            Message::LedgerUpdate {
+                sequence,
+                transaction,
+            } => {
+                self.check_sequence(sequence)?;
+                self.ledger.insert(transaction.clone())?;
+                self.apply_transaction(&transaction);
+                self.add_transactions(1);
+                Ok(())
+            }
+            // Does not affect the ledger
+            Message::TransactionRejected { .. } | Message::TransactionBatchResult { .. } => Ok(()),
+            _ => Err(Error::UnexpectedMessage(format!("{msg:?}"))),
+        }
+    }
+
+    fn apply_transaction(&self, transaction: &Transaction<OpType>) {
+        if let Some(state_machine) = &self.state_machine {
+            // Invalid transactions are part of the ledger but do not change the state
+            let _ = state_machine.lock().unwrap().apply(transaction);
+        }
+    }
+
+    fn check_sequence(&self, sequence: SequenceNumber) -> Result<(), Error> {
+        let mut last_sequence = self.last_sequence.lock().unwrap();
+
+        // The first update can have any sequence number as we might have joined late
+        if let Some(last) = *last_sequence {
+            if sequence != last + 1 {
+                return Err(Error::OutOfOrder {
+                    expected: last + 1,
+                    received: sequence,
+                });
+            }
+        }
+
+        *last_sequence = Some(sequence);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::LedgerMirror;
+    use crate::protocol::Message;
+    use crate::{
+        generate_key_pair, to_account_id, Error, Ledger, StateError, StateMachine, TestOperation,
+        Transaction,
+    };
+
+    /// Counts all transactions it was given
+    #[derive(Default)]
+    struct TxCounter {
+        count: u64,
+    }
+
+    impl StateMachine<TestOperation> for TxCounter {
+        fn apply(&mut self, _tx: &Transaction<TestOperation>) -> Result<(), StateError> {
+            self.count += 1;
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            self.count.to_le_bytes().to_vec()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+            self.count = u64::from_le_bytes(snapshot.try_into().unwrap());
+            Ok(())
+        }
+    }
+
+    fn new_epoch(sequence: u64, identifier: u32) -> Message<TestOperation> {
+        Message::NewEpochStarted {
+            sequence,
+            identifier,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn sequence_gap() {
+        let mirror = LedgerMirror::<TestOperation>::default();
+
+        mirror.handle_message(new_epoch(5, 0)).unwrap();
+        mirror.handle_message(new_epoch(6, 1)).unwrap();
+
+        assert!(matches!(
+            mirror.handle_message(new_epoch(8, 2)),
+            Err(Error::OutOfOrder {
+                expected: 7,
+                received: 8
+            })
+        ));
+        assert_eq!(mirror.get_ledger().num_epochs(), 2);
+    }
+
+    #[test]
+    fn mismatched_sync() {
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let server = Ledger::<TestOperation>::default();
+        server.create_new_epoch(0, 0).unwrap();
+        server
+            .insert(Transaction::new(account, TestOperation::Empty {}, skey))
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(TxCounter::default()));
+        let mirror = LedgerMirror::with_state_machine(state.clone());
+        mirror.handle_message(new_epoch(0, 0)).unwrap();
+
+        // The mirror already has an epoch with this identifier
+        let result = mirror.handle_message(Message::SyncEpoch {
+            identifier: 0,
+            epoch: server.get_epoch(0).unwrap(),
+        });
+
+        assert!(matches!(result, Err(Error::EpochAlreadyExists(0))));
+        assert_eq!(state.lock().unwrap().count, 0);
+        assert_eq!(mirror.get_ledger().num_transactions(), 0);
+    }
+}
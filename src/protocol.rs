@@ -1,11 +1,67 @@
-use crate::transactions::Transaction;
-use crate::{Epoch, OpTrait};
+use crate::transactions::{Transaction, TransactionId};
+use crate::{Epoch, EpochStatistics, OpTrait, PublicKey};
+
+use bytes::Bytes;
 
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
 pub type EpochId = u32;
 
+/// Orders all messages that modify the ledger
+///
+/// Every peer receives these messages with consecutive sequence numbers,
+/// starting after the epochs it got during the initial sync.
+pub type SequenceNumber = u64;
+
+/// Prepended to the nonce of an `AuthChallenge` before it is signed
+///
+/// This separates challenge signatures from transaction signatures, so whoever sends the
+/// challenge cannot choose the nonce to obtain a signature for arbitrary data.
+pub const AUTH_CONTEXT: &[u8] = b"blocksim-auth\0";
+
+/// The data a client has to sign to answer the `AuthChallenge` with the given nonce
+pub fn auth_payload(nonce: &[u8]) -> Vec<u8> {
+    [AUTH_CONTEXT, nonce].concat()
+}
+
+/// Why the server dropped a transaction instead of committing it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The validity window of the transaction has passed
+    Expired,
+    /// The validity window of the transaction has not started yet
+    NotYetValid,
+    /// The transaction would not even fit into an empty epoch
+    ExceedsEpochCapacity,
+    /// The transaction lacks a valid signature (or enough multisig signatures) of its source
+    Unauthorized,
+    /// The transaction failed validation or was not issued by the authenticated account
+    Invalid,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => write!(f, "Transaction expired"),
+            Self::NotYetValid => write!(f, "Transaction is not valid yet"),
+            Self::ExceedsEpochCapacity => write!(f, "Transaction exceeds the capacity of an epoch"),
+            Self::Unauthorized => write!(f, "Transaction is not authorized by its source"),
+            Self::Invalid => write!(f, "Transaction failed validation"),
+        }
+    }
+}
+
+/// What happened to a transaction that was submitted as part of a batch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchItemResult {
+    /// The transaction was added to the ledger (and will be broadcast as a `LedgerUpdate`)
+    Committed,
+    /// The transaction failed validation or was not issued by the authenticated account
+    Discarded,
+    Rejected(RejectReason),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message<OpType: OpTrait> {
     // Send an entire epoch. Only done during initial connection setup
@@ -17,12 +73,21 @@ pub enum Message<OpType: OpTrait> {
     // A new epoch has started
     // (e.g. a new key block was mined)
     NewEpochStarted {
+        sequence: SequenceNumber,
         identifier: EpochId,
         timestamp: i64,
     },
 
+    // An epoch ended and will not receive any more transactions
+    EpochSealed {
+        sequence: SequenceNumber,
+        identifier: EpochId,
+        statistics: EpochStatistics,
+    },
+
     // A new transaction was added to the chain
     LedgerUpdate {
+        sequence: SequenceNumber,
         transaction: Transaction<OpType>,
     },
 
@@ -30,4 +95,37 @@ pub enum Message<OpType: OpTrait> {
     TransactionRequest {
         transaction: Transaction<OpType>,
     },
+
+    // Send by clients to submit multiple transactions at once
+    // Transactions are admitted in order, each counting against the throughput limit
+    TransactionBatch {
+        transactions: Vec<Transaction<OpType>>,
+    },
+
+    // Sent to the submitter of a batch once all of its transactions were processed
+    // Contains one result per transaction, in the order of the batch
+    TransactionBatchResult {
+        results: Vec<BatchItemResult>,
+    },
+
+    // Send by clients to end the current epoch (only if the server uses an external trigger)
+    TriggerEpoch,
+
+    // Sent to the submitter of a transaction that was dropped instead of being committed
+    TransactionRejected {
+        id: TransactionId,
+        reason: RejectReason,
+    },
+
+    // Sent by the server right after the encoding handshake if authentication is required
+    AuthChallenge {
+        nonce: Bytes,
+    },
+
+    // Sent by clients to prove they own the account with the given public key
+    // The signature covers `auth_payload(nonce)` of the challenge
+    AuthResponse {
+        public_key: PublicKey,
+        signature: Bytes,
+    },
 }
@@ -0,0 +1,211 @@
+use std::sync::Weak;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::server::ledger_wrapper::LedgerWrapper;
+use crate::transactions::Transaction;
+use crate::{Error, OpTrait};
+
+use log::trace;
+
+/// Token bucket that limits how fast transactions are admitted to the ledger
+///
+/// This is implemented as a generic cell rate algorithm (GCRA), i.e., instead of
+/// refilling tokens it tracks the theoretical arrival time of the next transaction.
+/// Up to `burst` transactions can be admitted at once, while the long-term rate
+/// never exceeds `throughput`.
+pub struct RateLimiter {
+    interval: Duration,
+    tolerance: Duration,
+    next_arrival: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Fails if the throughput is not positive or so small that the interval between two
+    /// transactions cannot be represented
+    pub fn new(throughput: f64, burst: u32) -> Result<Self, Error> {
+        if throughput.is_nan() || throughput <= 0.0 {
+            return Err(Error::InvalidConfig(
+                "Throughput must be positive".to_string(),
+            ));
+        }
+
+        if burst == 0 {
+            return Err(Error::InvalidConfig(
+                "Burst size cannot be zero".to_string(),
+            ));
+        }
+
+        let too_small = || Error::InvalidConfig(format!("Throughput {throughput} is too small"));
+
+        let interval = Duration::try_from_secs_f64(1.0 / throughput).map_err(|_| too_small())?;
+        let tolerance = interval.checked_mul(burst - 1).ok_or_else(too_small)?;
+
+        Ok(Self {
+            interval,
+            tolerance,
+            next_arrival: None,
+        })
+    }
+
+    /// Reserves a slot for a transaction submitted at `arrival`
+    ///
+    /// Returns the time at which the transaction may be admitted.
+    pub fn reserve(&mut self, arrival: Instant) -> Instant {
+        let expected = match self.next_arrival {
+            Some(next) if next > arrival => next,
+            _ => arrival,
+        };
+
+        self.next_arrival = Some(expected + self.interval);
+
+        if expected > arrival + self.tolerance {
+            expected - self.tolerance
+        } else {
+            arrival
+        }
+    }
+}
+
+pub(super) struct AdmissionRequest<OpType: OpTrait> {
+    pub transactions: Vec<Transaction<OpType>>,
+    pub arrival: Instant,
+    /// One result per transaction, in the same order
+    pub results: oneshot::Sender<Vec<Result<(), Error>>>,
+}
+
+/// Admits transactions in the order they were submitted
+///
+/// Submitters do not contend on a lock; they only enqueue their transactions and wait for the
+/// results. Because the slot of a transaction is computed from its arrival time, a backlog is
+/// drained at the configured rate even if the timer fires late. The transactions of a batch
+/// share one request, but each of them reserves its own slot.
+///
+/// If the current epoch is at capacity, this (and all following transactions) wait for the
+/// next epoch to start. Once the ledger is closed, all remaining transactions are rejected.
+pub(super) async fn run_admission<OpType: OpTrait + Serialize + DeserializeOwned>(
+    ledger: Weak<LedgerWrapper<OpType>>,
+    mut limiter: RateLimiter,
+    mut requests: mpsc::UnboundedReceiver<AdmissionRequest<OpType>>,
+    closed: CancellationToken,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut results = Vec::with_capacity(request.transactions.len());
+
+        for transaction in &request.transactions {
+            let result =
+                admit_transaction(&ledger, &mut limiter, transaction, request.arrival, &closed)
+                    .await;
+
+            let Some(result) = result else {
+                return;
+            };
+            results.push(result);
+        }
+
+        // The submitter might have gone away in the meantime
+        let _ = request.results.send(results);
+    }
+}
+
+/// Waits for the slot of the transaction and commits it
+///
+/// Returns None if the ledger does not exist anymore.
+async fn admit_transaction<OpType: OpTrait + Serialize + DeserializeOwned>(
+    ledger: &Weak<LedgerWrapper<OpType>>,
+    limiter: &mut RateLimiter,
+    transaction: &Transaction<OpType>,
+    arrival: Instant,
+    closed: &CancellationToken,
+) -> Option<Result<(), Error>> {
+    let release = limiter.reserve(arrival);
+
+    if release > Instant::now() {
+        tokio::select! {
+            _ = sleep_until(release) => {}
+            _ = closed.cancelled() => {}
+        }
+    }
+
+    let mut admission_wait = Some(arrival.elapsed());
+
+    loop {
+        let ledger = ledger.upgrade()?;
+
+        // Subscribe before committing so that we cannot miss the start of a new epoch
+        let mut epochs = ledger.subscribe_epochs();
+
+        let metrics = ledger.metrics();
+        if let Some(wait) = admission_wait.take() {
+            metrics.admission_wait.observe_duration(wait);
+        }
+
+        match ledger.commit(transaction).await {
+            Ok(false) => {}
+            Ok(true) => {
+                metrics.commit_latency.observe_duration(arrival.elapsed());
+                return Some(Ok(()));
+            }
+            Err(err) => return Some(Err(err)),
+        }
+
+        trace!("Current epoch is full; holding back transactions until the next one");
+
+        drop(ledger);
+        tokio::select! {
+            result = epochs.changed() => {
+                result.ok()?;
+            }
+            _ = closed.cancelled() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::RateLimiter;
+
+    #[test]
+    fn burst() {
+        let mut limiter = RateLimiter::new(1000.0, 10).unwrap();
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(start), start);
+        }
+
+        assert_eq!(limiter.reserve(start), start + Duration::from_millis(1));
+        assert_eq!(limiter.reserve(start), start + Duration::from_millis(2));
+
+        // Unused capacity does not accumulate beyond the burst size
+        let later = start + Duration::from_secs(10);
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(later), later);
+        }
+        assert_eq!(limiter.reserve(later), later + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn sustained_rate() {
+        let num_txs = 100_000;
+        let mut limiter = RateLimiter::new(100_000.0, 1).unwrap();
+        let start = Instant::now();
+
+        let mut last = start;
+        for _ in 0..num_txs {
+            last = limiter.reserve(start);
+        }
+
+        assert_eq!(last - start, Duration::from_micros(10) * (num_txs - 1));
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+
+use crate::AccountId;
+
+/// Decides which peers may connect to the server
+///
+/// If authentication is required, peers have to sign a challenge with the
+/// key of their account before they receive any ledger data.
+#[derive(Default)]
+pub struct AuthPolicy {
+    required: bool,
+    allowed_accounts: HashSet<AccountId>,
+}
+
+impl AuthPolicy {
+    /// Does not require peers to authenticate
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Requires peers to authenticate
+    ///
+    /// If `allowed_accounts` is empty, any account may connect.
+    pub fn required(allowed_accounts: impl IntoIterator<Item = AccountId>) -> Self {
+        Self {
+            required: true,
+            allowed_accounts: allowed_accounts.into_iter().collect(),
+        }
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    pub fn is_allowed(&self, account: AccountId) -> bool {
+        self.allowed_accounts.is_empty() || self.allowed_accounts.contains(&account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use super::AuthPolicy;
+    use crate::encoding::{Encoding, WireCodec};
+    use crate::protocol::{auth_payload, Message};
+    use crate::server::connection::PeerConnection;
+    use crate::server::ledger_wrapper::LedgerWrapper;
+    use crate::server::outbound::QueueConfig;
+    use crate::server::NullCallback;
+    use crate::{generate_key_pair, sign, to_account_id, Error, TestOperation};
+
+    async fn run_authentication(
+        policy: AuthPolicy,
+        use_valid_key: bool,
+    ) -> (Result<Option<u64>, Error>, u64) {
+        let (server_side, client_side) = tokio::io::duplex(64 * 1024);
+        let encoding = Encoding::Bincode;
+
+        let ledger = LedgerWrapper::<TestOperation>::new(
+            1000.0,
+            1,
+            0,
+            Default::default(),
+            Default::default(),
+        );
+        let (mut conn, mut read_framed) = PeerConnection::new(
+            1,
+            ledger,
+            Arc::new(NullCallback {}),
+            server_side,
+            encoding,
+            QueueConfig::default(),
+        );
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let client = tokio::spawn(async move {
+            let mut framed = Framed::new(client_side, WireCodec::new(encoding));
+
+            let data = framed.next().await.unwrap().unwrap();
+            let Message::<TestOperation>::AuthChallenge { nonce } = encoding.decode(&data).unwrap()
+            else {
+                panic!("Expected challenge");
+            };
+
+            // A signature of the bare nonce must not be accepted
+            let signature = if use_valid_key {
+                sign(&private_key, &auth_payload(&nonce))
+            } else {
+                sign(&private_key, &nonce)
+            };
+
+            let response = Message::<TestOperation>::AuthResponse {
+                public_key,
+                signature,
+            };
+            let data = encoding.encode(&response).unwrap();
+            framed.send(data.into()).await.unwrap();
+            framed
+        });
+
+        let result = conn.authenticate(&mut read_framed, &policy).await;
+        let _framed = client.await.unwrap();
+
+        (result, account)
+    }
+
+    #[tokio::test]
+    async fn authenticate() {
+        let (result, account) = run_authentication(AuthPolicy::required([]), true).await;
+        assert_eq!(result.unwrap(), Some(account));
+
+        let (result, _) = run_authentication(AuthPolicy::required([]), false).await;
+        assert!(matches!(result, Err(Error::AuthenticationFailed(_))));
+
+        let (result, _) = run_authentication(AuthPolicy::required([42]), true).await;
+        assert!(matches!(result, Err(Error::AuthenticationFailed(_))));
+    }
+}
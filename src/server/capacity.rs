@@ -0,0 +1,100 @@
+use serde::Serialize;
+
+use crate::transactions::Transaction;
+
+/// Limits how many transactions fit into a single epoch
+///
+/// Transactions that do not fit into the current epoch are held back until
+/// the next one starts. Each limit is optional; by default epochs are unbounded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EpochCapacity {
+    pub max_transactions: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub max_cost: Option<u64>,
+}
+
+/// Resources used by the transactions of an epoch so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EpochUsage {
+    pub transactions: usize,
+    pub bytes: u64,
+    pub cost: u64,
+}
+
+impl EpochUsage {
+    pub fn add<OpType: Serialize + std::fmt::Debug>(&mut self, tx: &Transaction<OpType>) {
+        self.transactions += 1;
+        self.bytes += tx.byte_size();
+        self.cost += tx.get_cost();
+    }
+}
+
+impl EpochCapacity {
+    pub fn is_unbounded(&self) -> bool {
+        self.max_transactions.is_none() && self.max_bytes.is_none() && self.max_cost.is_none()
+    }
+
+    /// Would the epoch still be within its capacity after adding this transaction?
+    pub fn fits<OpType: Serialize + std::fmt::Debug>(
+        &self,
+        usage: &EpochUsage,
+        tx: &Transaction<OpType>,
+    ) -> bool {
+        let mut usage = *usage;
+        usage.add(tx);
+
+        self.max_transactions
+            .is_none_or(|max| usage.transactions <= max)
+            && self.max_bytes.is_none_or(|max| usage.bytes <= max)
+            && self.max_cost.is_none_or(|max| usage.cost <= max)
+    }
+
+    /// Transactions that do not even fit into an empty epoch can never be committed
+    pub fn fits_empty<OpType: Serialize + std::fmt::Debug>(
+        &self,
+        tx: &Transaction<OpType>,
+    ) -> bool {
+        self.fits(&EpochUsage::default(), tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EpochCapacity, EpochUsage};
+    use crate::{generate_key_pair, to_account_id, TestOperation, Transaction};
+
+    #[test]
+    fn limits() {
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let cheap = Transaction::new(account, TestOperation::Empty {}, skey.clone());
+        let expensive = Transaction::new_with_cost(account, TestOperation::Empty {}, 10, skey);
+
+        let capacity = EpochCapacity {
+            max_transactions: Some(3),
+            max_cost: Some(11),
+            ..Default::default()
+        };
+
+        assert!(capacity.fits_empty(&expensive));
+
+        let mut usage = EpochUsage::default();
+        usage.add(&cheap);
+        assert!(capacity.fits(&usage, &expensive));
+
+        usage.add(&cheap);
+        assert!(!capacity.fits(&usage, &expensive));
+
+        usage.add(&cheap);
+        assert!(!capacity.fits(&usage, &cheap));
+        assert_eq!(usage.cost, 3);
+
+        let bytes = EpochCapacity {
+            max_bytes: Some(cheap.byte_size() - 1),
+            ..Default::default()
+        };
+        assert!(!bytes.fits_empty(&cheap));
+        assert!(EpochCapacity::default().is_unbounded());
+    }
+}
@@ -0,0 +1,472 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::server::admission::RateLimiter;
+use crate::server::trace::TraceFormat;
+use crate::server::{
+    AuthPolicy, Callback, EpochCapacity, EpochTrigger, OverflowPolicy, QueueConfig,
+    DEFAULT_METRICS_PORT,
+};
+use crate::{AccountId, Error, OpTrait, SharedStateMachine, DEFAULT_BLOCKCHAIN_PORT};
+
+/// Resolves an address, using the default port if none is given
+fn parse_address(addr_str: &str, default_port: u16) -> Result<SocketAddr, Error> {
+    let addr_str = if addr_str.contains(':') {
+        addr_str.to_string()
+    } else {
+        format!("{addr_str}:{default_port}")
+    };
+
+    addr_str
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(Error::InvalidConfig(format!("Invalid address: {addr_str}")))
+}
+
+/// Everything needed to run a server; created using `ServerConfig::builder`
+pub struct ServerConfig<OpType: OpTrait> {
+    /// None if clients can only connect in-memory
+    pub(super) listen_address: Option<SocketAddr>,
+    pub(super) throughput: f64,
+    pub(super) burst: u32,
+    pub(super) latency_ms: u32,
+    pub(super) capacity: EpochCapacity,
+    pub(super) epoch_trigger: EpochTrigger,
+    pub(super) queue: QueueConfig,
+    pub(super) auth_policy: AuthPolicy,
+    #[cfg(feature = "gateway")]
+    pub(super) http_address: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    pub(super) tls: Option<(PathBuf, PathBuf)>,
+    pub(super) storage: Option<PathBuf>,
+    pub(super) metrics_address: Option<SocketAddr>,
+    pub(super) trace: Option<(PathBuf, TraceFormat)>,
+    pub(super) callback: Arc<dyn Callback<OpType>>,
+    pub(super) state_machine: Option<SharedStateMachine<OpType>>,
+}
+
+impl<OpType: OpTrait> ServerConfig<OpType> {
+    /// Starts with the same defaults as the command line of the server binary
+    pub fn builder(callback: Arc<dyn Callback<OpType>>) -> ServerConfigBuilder<OpType> {
+        ServerConfigBuilder {
+            config: Self {
+                listen_address: Some(SocketAddr::from(([0, 0, 0, 0], DEFAULT_BLOCKCHAIN_PORT))),
+                throughput: 1000.0,
+                burst: 1,
+                latency_ms: 100,
+                capacity: EpochCapacity::default(),
+                epoch_trigger: EpochTrigger::default(),
+                queue: QueueConfig::default(),
+                auth_policy: AuthPolicy::none(),
+                #[cfg(feature = "gateway")]
+                http_address: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                storage: None,
+                metrics_address: None,
+                trace: None,
+                callback,
+                state_machine: None,
+            },
+        }
+    }
+
+    pub fn get_listen_address(&self) -> Option<SocketAddr> {
+        self.listen_address
+    }
+
+    pub fn get_epoch_trigger(&self) -> EpochTrigger {
+        self.epoch_trigger
+    }
+}
+
+pub struct ServerConfigBuilder<OpType: OpTrait> {
+    config: ServerConfig<OpType>,
+}
+
+impl<OpType: OpTrait> ServerConfigBuilder<OpType> {
+    /// The address to listen for client connections (use port 0 to pick any free port)
+    pub fn listen_address(mut self, addr: SocketAddr) -> Self {
+        self.config.listen_address = Some(addr);
+        self
+    }
+
+    /// Do not listen for TCP connections; clients can only connect using `ServerHandle::connect`
+    pub fn in_memory(mut self) -> Self {
+        self.config.listen_address = None;
+        self
+    }
+
+    /// The maximum throughput of the chain (in tx/s)
+    pub fn throughput(mut self, throughput: f64) -> Self {
+        self.config.throughput = throughput;
+        self
+    }
+
+    /// How many transactions can be admitted at once without exceeding the throughput
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.config.burst = burst;
+        self
+    }
+
+    /// The transaction confirmation delay (in ms)
+    pub fn latency_ms(mut self, latency_ms: u32) -> Self {
+        self.config.latency_ms = latency_ms;
+        self
+    }
+
+    pub fn epoch_capacity(mut self, capacity: EpochCapacity) -> Self {
+        self.config.capacity = capacity;
+        self
+    }
+
+    pub fn epoch_trigger(mut self, trigger: EpochTrigger) -> Self {
+        self.config.epoch_trigger = trigger;
+        self
+    }
+
+    pub fn queue(mut self, queue: QueueConfig) -> Self {
+        self.config.queue = queue;
+        self
+    }
+
+    pub fn auth_policy(mut self, policy: AuthPolicy) -> Self {
+        self.config.auth_policy = policy;
+        self
+    }
+
+    /// Serve the HTTP/WebSocket gateway on the given address
+    #[cfg(feature = "gateway")]
+    pub fn http_address(mut self, addr: SocketAddr) -> Self {
+        self.config.http_address = Some(addr);
+        self
+    }
+
+    /// Use TLS for client connections with the given PEM files
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, cert: PathBuf, key: PathBuf) -> Self {
+        self.config.tls = Some((cert, key));
+        self
+    }
+
+    /// Write every sealed epoch to the given file (see `storage::read_epochs`)
+    pub fn storage(mut self, path: PathBuf) -> Self {
+        self.config.storage = Some(path);
+        self
+    }
+
+    /// Serve the metrics on the given address (see `Metrics::render`)
+    pub fn metrics_address(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics_address = Some(addr);
+        self
+    }
+
+    /// Record all events on the server to the given file (see `trace::read_trace`)
+    pub fn trace(mut self, path: PathBuf, format: TraceFormat) -> Self {
+        self.config.trace = Some((path, format));
+        self
+    }
+
+    /// Drive the given state machine from all committed transactions
+    pub fn state_machine(mut self, state_machine: SharedStateMachine<OpType>) -> Self {
+        self.config.state_machine = Some(state_machine);
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig<OpType>, Error> {
+        let config = self.config;
+
+        RateLimiter::new(config.throughput, config.burst)?;
+
+        if config.queue.capacity == 0 {
+            return Err(Error::InvalidConfig(
+                "Queue size cannot be zero".to_string(),
+            ));
+        }
+
+        // The gateway serves plain HTTP and does not authenticate clients
+        #[cfg(feature = "gateway")]
+        if config.http_address.is_some() {
+            if config.auth_policy.is_required() {
+                return Err(Error::InvalidConfig(
+                    "The HTTP gateway cannot be used if authentication is required".to_string(),
+                ));
+            }
+
+            #[cfg(feature = "tls")]
+            if config.tls.is_some() {
+                return Err(Error::InvalidConfig(
+                    "The HTTP gateway cannot be used together with TLS".to_string(),
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The contents of a TOML configuration file
+///
+/// All entries are optional and use the same names as the command line flags
+/// (with underscores instead of dashes).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub listen_address: Option<String>,
+    pub throughput: Option<f64>,
+    pub burst: Option<u32>,
+    pub latency: Option<u32>,
+    /// Shorthand for an `interval` trigger (in s); ignored if `epoch_trigger` is set
+    pub epoch_length: Option<u64>,
+    pub epoch_trigger: Option<EpochTrigger>,
+    pub max_epoch_transactions: Option<usize>,
+    pub max_epoch_bytes: Option<u64>,
+    pub max_epoch_cost: Option<u64>,
+    pub queue_size: Option<usize>,
+    pub queue_policy: Option<OverflowPolicy>,
+    pub require_auth: Option<bool>,
+    pub allowed_accounts: Option<Vec<AccountId>>,
+    #[cfg(feature = "gateway")]
+    pub http_address: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub tls_key: Option<PathBuf>,
+    pub storage: Option<PathBuf>,
+    pub metrics_address: Option<String>,
+    pub trace: Option<PathBuf>,
+    pub trace_format: Option<TraceFormat>,
+}
+
+impl FromStr for ConfigFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| Error::InvalidConfig(err.to_string()))
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Entries set in `overrides` replace the ones of this file
+    pub fn merge(self, overrides: Self) -> Self {
+        Self {
+            listen_address: overrides.listen_address.or(self.listen_address),
+            throughput: overrides.throughput.or(self.throughput),
+            burst: overrides.burst.or(self.burst),
+            latency: overrides.latency.or(self.latency),
+            epoch_length: overrides.epoch_length.or(self.epoch_length),
+            epoch_trigger: overrides.epoch_trigger.or(self.epoch_trigger),
+            max_epoch_transactions: overrides
+                .max_epoch_transactions
+                .or(self.max_epoch_transactions),
+            max_epoch_bytes: overrides.max_epoch_bytes.or(self.max_epoch_bytes),
+            max_epoch_cost: overrides.max_epoch_cost.or(self.max_epoch_cost),
+            queue_size: overrides.queue_size.or(self.queue_size),
+            queue_policy: overrides.queue_policy.or(self.queue_policy),
+            require_auth: overrides.require_auth.or(self.require_auth),
+            allowed_accounts: overrides.allowed_accounts.or(self.allowed_accounts),
+            #[cfg(feature = "gateway")]
+            http_address: overrides.http_address.or(self.http_address),
+            #[cfg(feature = "tls")]
+            tls_cert: overrides.tls_cert.or(self.tls_cert),
+            #[cfg(feature = "tls")]
+            tls_key: overrides.tls_key.or(self.tls_key),
+            storage: overrides.storage.or(self.storage),
+            metrics_address: overrides.metrics_address.or(self.metrics_address),
+            trace: overrides.trace.or(self.trace),
+            trace_format: overrides.trace_format.or(self.trace_format),
+        }
+    }
+
+    /// Sets all entries of this file on the builder; others keep their current value
+    pub fn apply<OpType: OpTrait>(
+        self,
+        mut builder: ServerConfigBuilder<OpType>,
+    ) -> Result<ServerConfigBuilder<OpType>, Error> {
+        if let Some(addr) = &self.listen_address {
+            builder = builder.listen_address(parse_address(addr, DEFAULT_BLOCKCHAIN_PORT)?);
+        }
+
+        if let Some(throughput) = self.throughput {
+            builder = builder.throughput(throughput);
+        }
+
+        if let Some(burst) = self.burst {
+            builder = builder.burst(burst);
+        }
+
+        if let Some(latency) = self.latency {
+            builder = builder.latency_ms(latency);
+        }
+
+        let interval = self
+            .epoch_length
+            .map(|secs| EpochTrigger::Interval(Duration::from_secs(secs)));
+        if let Some(trigger) = self.epoch_trigger.or(interval) {
+            builder = builder.epoch_trigger(trigger);
+        }
+
+        let capacity = &mut builder.config.capacity;
+        capacity.max_transactions = self.max_epoch_transactions.or(capacity.max_transactions);
+        capacity.max_bytes = self.max_epoch_bytes.or(capacity.max_bytes);
+        capacity.max_cost = self.max_epoch_cost.or(capacity.max_cost);
+
+        let queue = &mut builder.config.queue;
+        queue.capacity = self.queue_size.unwrap_or(queue.capacity);
+        queue.policy = self.queue_policy.unwrap_or(queue.policy);
+
+        let allowed_accounts = self.allowed_accounts.unwrap_or_default();
+        if self.require_auth.unwrap_or(false) || !allowed_accounts.is_empty() {
+            builder = builder.auth_policy(AuthPolicy::required(allowed_accounts));
+        }
+
+        #[cfg(feature = "gateway")]
+        if let Some(addr) = &self.http_address {
+            let addr = parse_address(addr, crate::server::DEFAULT_GATEWAY_PORT)?;
+            builder = builder.http_address(addr);
+        }
+
+        #[cfg(feature = "tls")]
+        match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => builder = builder.tls(cert, key),
+            (None, None) => {}
+            _ => {
+                return Err(Error::InvalidConfig(
+                    "TLS needs both a certificate and a key".to_string(),
+                ))
+            }
+        }
+
+        if let Some(path) = self.storage {
+            builder = builder.storage(path);
+        }
+
+        if let Some(addr) = &self.metrics_address {
+            let addr = parse_address(addr, DEFAULT_METRICS_PORT)?;
+            builder = builder.metrics_address(addr);
+        }
+
+        match (self.trace, self.trace_format) {
+            (Some(path), format) => {
+                let format = format.unwrap_or_else(|| TraceFormat::from_path(&path));
+                builder = builder.trace(path, format);
+            }
+            (None, None) => {}
+            (None, Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "A trace format was given without a trace file".to_string(),
+                ))
+            }
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{ConfigFile, ServerConfig};
+    use crate::server::{EpochTrigger, NullCallback, OverflowPolicy};
+    use crate::{Error, TestOperation};
+
+    #[test]
+    fn file_and_overrides() {
+        let file: ConfigFile = r#"
+            listen_address = "127.0.0.1:9000"
+            throughput = 50.0
+            epoch_trigger = "transactions:100"
+            max_epoch_bytes = 4096
+            queue_policy = "drop-oldest"
+            allowed_accounts = [1, 2]
+        "#
+        .parse()
+        .unwrap();
+
+        let overrides = ConfigFile {
+            throughput: Some(10.0),
+            epoch_trigger: Some(EpochTrigger::Interval(Duration::from_secs(5))),
+            ..Default::default()
+        };
+
+        let callback = Arc::new(NullCallback {});
+        let config = file
+            .merge(overrides)
+            .apply(ServerConfig::<TestOperation>::builder(callback))
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            config.listen_address,
+            Some("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(config.throughput, 10.0);
+        assert_eq!(config.burst, 1);
+        assert_eq!(
+            config.epoch_trigger,
+            EpochTrigger::Interval(Duration::from_secs(5))
+        );
+        assert_eq!(config.capacity.max_bytes, Some(4096));
+        assert_eq!(config.capacity.max_transactions, None);
+        assert_eq!(config.queue.policy, OverflowPolicy::DropOldest);
+        assert!(config.auth_policy.is_required());
+        assert!(!config.auth_policy.is_allowed(3));
+
+        let config = "epoch_length = 10"
+            .parse::<ConfigFile>()
+            .unwrap()
+            .apply(ServerConfig::<TestOperation>::builder(Arc::new(
+                NullCallback {},
+            )))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.epoch_trigger,
+            EpochTrigger::Interval(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let callback = Arc::new(NullCallback {});
+
+        assert!(matches!(
+            "throughput = 10.0\nunknown = 1".parse::<ConfigFile>(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!("epoch_trigger = \"sometimes\""
+            .parse::<ConfigFile>()
+            .is_err());
+
+        for throughput in [0.0, 1e-300] {
+            let result = ServerConfig::<TestOperation>::builder(callback.clone())
+                .throughput(throughput)
+                .build();
+            assert!(matches!(result, Err(Error::InvalidConfig(_))));
+        }
+
+        // The gateway would bypass authentication
+        #[cfg(feature = "gateway")]
+        {
+            let result = ServerConfig::<TestOperation>::builder(callback)
+                .auth_policy(crate::server::AuthPolicy::required([]))
+                .http_address("127.0.0.1:0".parse().unwrap())
+                .build();
+            assert!(matches!(result, Err(Error::InvalidConfig(_))));
+        }
+    }
+}
@@ -1,7 +1,5 @@
-use tokio::net::TcpStream;
-use tokio::sync::Mutex as FMutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 
-use tokio_util::codec::length_delimited::LengthDelimitedCodec;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use futures::sink::SinkExt;
@@ -9,24 +7,46 @@ use futures::stream::StreamExt;
 
 use bytes::Bytes;
 
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::protocol::Message;
+use crate::encoding::{Encoding, WireCodec};
+use crate::protocol::{auth_payload, BatchItemResult, Message, RejectReason};
+use crate::server::auth::AuthPolicy;
+use crate::server::epochs::EpochTrigger;
 use crate::server::ledger_wrapper::LedgerWrapper;
+use crate::server::outbound::{OutboundQueue, QueueConfig, QueueStats};
+use crate::server::trace::TraceEvent;
 use crate::transactions::Transaction;
-use crate::OpTrait;
+use crate::{to_account_id, verify, AccountId, Error, OpTrait};
 
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+/// Any bidirectional byte stream a peer can be connected through (e.g., TCP or TLS)
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + 'static {}
 
-pub type PeerReadSocket = FramedRead<OwnedReadHalf, LengthDelimitedCodec>;
-pub type PeerWriteSocket = FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>;
+impl<S: AsyncRead + AsyncWrite + Send + 'static> PeerStream for S {}
+
+type BoxedStream = Pin<Box<dyn PeerStream>>;
+
+pub type PeerReadSocket = FramedRead<ReadHalf<BoxedStream>, WireCodec>;
+pub type PeerWriteSocket = FramedWrite<WriteHalf<BoxedStream>, WireCodec>;
+
+/// Length of the random nonce clients have to sign during authentication
+const AUTH_NONCE_LENGTH: usize = 32;
 
 pub trait Callback<Operation: OpTrait>: Sync + Send {
     fn validate_transaction(&self, tx: &Transaction<Operation>) -> bool;
 
+    /// Validates all transactions of a batch at once (e.g., to check them in parallel)
+    ///
+    /// Returns one flag per transaction. By default, transactions are validated one by one.
+    fn validate_batch(&self, txs: &[Transaction<Operation>]) -> Vec<bool> {
+        txs.iter().map(|tx| self.validate_transaction(tx)).collect()
+    }
+
     fn notify_new_transaction(&self, tx: &Transaction<Operation>);
 }
 
@@ -43,8 +63,10 @@ impl<Operation: OpTrait> Callback<Operation> for NullCallback {
 
 pub struct PeerConnection<Operation: OpTrait> {
     identifier: u32,
+    encoding: Encoding,
+    account: Option<AccountId>,
     ledger: Arc<LedgerWrapper<Operation>>,
-    write_framed: FMutex<PeerWriteSocket>,
+    outbound: Arc<OutboundQueue>,
     callback: Arc<dyn Callback<Operation>>,
 }
 
@@ -53,66 +75,344 @@ impl<Operation: OpTrait + Serialize + DeserializeOwned> PeerConnection<Operation
         identifier: u32,
         ledger: Arc<LedgerWrapper<Operation>>,
         callback: Arc<dyn Callback<Operation>>,
-        socket: TcpStream,
+        socket: impl PeerStream,
+        encoding: Encoding,
+        queue_config: QueueConfig,
     ) -> (Self, PeerReadSocket) {
-        let (read_socket, write_socket) = socket.into_split();
+        let socket: BoxedStream = Box::pin(socket);
+        let (read_socket, write_socket) = tokio::io::split(socket);
 
-        let read_framed = FramedRead::new(read_socket, LengthDelimitedCodec::new());
-        let write_framed = FMutex::new(FramedWrite::new(write_socket, LengthDelimitedCodec::new()));
+        let read_framed = FramedRead::new(read_socket, WireCodec::new(encoding));
+        let write_framed = FramedWrite::new(write_socket, WireCodec::new(encoding));
+
+        let outbound = Arc::new(OutboundQueue::new(queue_config));
+        let bytes_sent = ledger.metrics().bytes_sent_to(identifier);
+        tokio::spawn(run_writer(
+            identifier,
+            outbound.clone(),
+            write_framed,
+            bytes_sent,
+        ));
 
         (
             Self {
                 identifier,
+                encoding,
+                account: None,
                 callback,
                 ledger,
-                write_framed,
+                outbound,
             },
             read_framed,
         )
     }
 
+    /// Makes the peer prove it owns an account (if required by the policy)
+    ///
+    /// This must be called before the peer is registered with the ledger.
+    pub async fn authenticate(
+        &mut self,
+        read_framed: &mut PeerReadSocket,
+        policy: &AuthPolicy,
+    ) -> Result<Option<AccountId>, Error> {
+        if !policy.is_required() {
+            return Ok(None);
+        }
+
+        let nonce: [u8; AUTH_NONCE_LENGTH] = rand::random();
+        self.send(&Message::AuthChallenge {
+            nonce: Bytes::copy_from_slice(&nonce),
+        })
+        .await?;
+
+        let data = match read_framed.next().await {
+            Some(result) => result?,
+            None => {
+                return Err(Error::AuthenticationFailed(
+                    "Peer disconnected during authentication".to_string(),
+                ));
+            }
+        };
+
+        let (public_key, signature) = match self.encoding.decode(&data)? {
+            Message::<Operation>::AuthResponse {
+                public_key,
+                signature,
+            } => (public_key, signature),
+            msg => return Err(Error::UnexpectedMessage(format!("{msg:?}"))),
+        };
+
+        if !verify(&public_key, &auth_payload(&nonce), &signature) {
+            return Err(Error::AuthenticationFailed("Invalid signature".to_string()));
+        }
+
+        let account = to_account_id(&public_key);
+
+        if !policy.is_allowed(account) {
+            return Err(Error::AuthenticationFailed(format!(
+                "Account {account} is not allowed to connect"
+            )));
+        }
+
+        self.account = Some(account);
+        Ok(Some(account))
+    }
+
     pub async fn run(&self, mut read_framed: PeerReadSocket) {
-        while let Some(result) = read_framed.next().await {
-            match result {
-                Ok(data) => {
-                    self.handle_message(data.freeze()).await;
+        loop {
+            let result = tokio::select! {
+                result = read_framed.next() => result,
+                _ = self.outbound.closed() => {
+                    log::warn!("Outbound queue of peer {} was closed", self.identifier);
+                    break;
                 }
-                Err(e) => {
-                    println!("Error on decoding from socket; error = {:?}", e);
+            };
+
+            let data = match result {
+                Some(Ok(data)) => data,
+                None => break,
+                Some(Err(err)) => {
+                    log::warn!("Error on decoding from socket: {err}");
                     break;
                 }
+            };
+
+            if let Err(err) = self.handle_message(data.freeze()).await {
+                log::warn!("Disconnecting peer {}: {err}", self.identifier);
+                break;
             }
         }
 
         log::info!("Peer {} disconnected from blockchain-sim", self.identifier);
+        self.outbound.close();
         self.ledger.unregister_peer(self.identifier).await;
     }
 
-    pub async fn handle_message(&self, data: Bytes) {
-        let msg = bincode::deserialize(&data).unwrap();
+    pub async fn handle_message(&self, data: Bytes) -> Result<(), Error> {
+        let msg = self.encoding.decode(&data)?;
 
         match msg {
             Message::TransactionRequest { transaction } => {
-                if self.callback.validate_transaction(&transaction) {
+                let trace = self.ledger.trace();
+                let id = transaction.get_id();
+
+                self.record_received(&transaction);
+
+                let valid = self.is_own_transaction(&transaction)
+                    && self.callback.validate_transaction(&transaction);
+                trace.record(TraceEvent::TransactionValidated {
+                    transaction: id,
+                    valid,
+                });
+
+                if valid {
                     self.callback.notify_new_transaction(&transaction);
-                    self.ledger.insert(transaction).await;
+
+                    match self.ledger.insert(transaction).await {
+                        Ok(()) => {}
+                        Err(Error::Rejected(reason)) => {
+                            log::debug!("Dropped transaction: {reason}");
+                            self.send(&Message::TransactionRejected { id, reason })
+                                .await?;
+                        }
+                        // Closing the connection is up to the shutdown, so queued messages are still sent
+                        Err(Error::ServerShutdown) => {
+                            log::debug!("Dropped transaction because the server is shutting down");
+                        }
+                        Err(err) => return Err(err),
+                    }
                 } else {
                     log::debug!("Discarded transaction because validation failed: {transaction:?}");
+                    self.ledger
+                        .metrics()
+                        .transactions_discarded
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.send(&Message::TransactionRejected {
+                        id,
+                        reason: RejectReason::Invalid,
+                    })
+                    .await?;
                 }
+
+                Ok(())
+            }
+            Message::TransactionBatch { transactions } => self.handle_batch(transactions).await,
+            Message::TriggerEpoch => {
+                if self.ledger.get_epoch_trigger() != EpochTrigger::External {
+                    log::warn!(
+                        "Peer {} tried to trigger a new epoch, but epochs are triggered by {}",
+                        self.identifier,
+                        self.ledger.get_epoch_trigger()
+                    );
+                    return Ok(());
+                }
+
+                if let Err(err) = self.ledger.start_new_epoch().await {
+                    log::error!("Failed to start new epoch: {err}");
+                }
+
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedMessage(format!("{msg:?}"))),
+        }
+    }
+
+    /// Validates and submits all transactions of a batch, then reports their results
+    async fn handle_batch(&self, transactions: Vec<Transaction<Operation>>) -> Result<(), Error> {
+        let trace = self.ledger.trace();
+
+        let mut results = vec![BatchItemResult::Discarded; transactions.len()];
+        let mut positions = Vec::with_capacity(transactions.len());
+        let mut candidates = Vec::with_capacity(transactions.len());
+
+        for (position, transaction) in transactions.into_iter().enumerate() {
+            self.record_received(&transaction);
+
+            if self.is_own_transaction(&transaction) {
+                positions.push(position);
+                candidates.push(transaction);
+            } else {
+                trace.record(TraceEvent::TransactionValidated {
+                    transaction: transaction.get_id(),
+                    valid: false,
+                });
             }
-            _ => {
-                panic!("Server got unexpected message: {msg:?}");
+        }
+
+        let flags = self.callback.validate_batch(&candidates);
+
+        let mut valid_positions = Vec::with_capacity(candidates.len());
+        let mut valid_transactions = Vec::with_capacity(candidates.len());
+
+        for ((position, transaction), valid) in positions.into_iter().zip(candidates).zip(flags) {
+            trace.record(TraceEvent::TransactionValidated {
+                transaction: transaction.get_id(),
+                valid,
+            });
+
+            if valid {
+                self.callback.notify_new_transaction(&transaction);
+                valid_positions.push(position);
+                valid_transactions.push(transaction);
+            } else {
+                log::debug!("Discarded transaction because validation failed: {transaction:?}");
             }
         }
+
+        let num_discarded = results.len() - valid_transactions.len();
+        self.ledger
+            .metrics()
+            .transactions_discarded
+            .fetch_add(num_discarded as u64, Ordering::Relaxed);
+
+        let outcomes = self.ledger.insert_batch(valid_transactions).await;
+
+        for (position, outcome) in valid_positions.into_iter().zip(outcomes) {
+            results[position] = match outcome {
+                Ok(()) => BatchItemResult::Committed,
+                Err(Error::Rejected(reason)) => {
+                    log::debug!("Dropped transaction: {reason}");
+                    BatchItemResult::Rejected(reason)
+                }
+                // Closing the connection is up to the shutdown, so queued messages are still sent
+                Err(Error::ServerShutdown) => {
+                    log::debug!("Dropped batch because the server is shutting down");
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+        }
+
+        self.send(&Message::TransactionBatchResult { results })
+            .await
     }
 
-    pub async fn send(&self, msg: &Message<Operation>) {
-        let data = bincode::serialize(msg).expect("Failed to serialize data");
-        let mut framed = self.write_framed.lock().await;
-        let result = framed.send(data.into()).await;
+    fn record_received(&self, transaction: &Transaction<Operation>) {
+        self.ledger.trace().record(TraceEvent::transaction_received(
+            transaction,
+            Some(self.identifier),
+        ));
+    }
 
-        if let Err(err) = result {
-            log::error!("Failed to send data to peer: {err}");
+    /// Authenticated peers may only submit transactions of their own account
+    fn is_own_transaction(&self, transaction: &Transaction<Operation>) -> bool {
+        match self.account {
+            Some(account) if *transaction.get_source() != account => {
+                log::debug!(
+                    "Discarded transaction because it was not issued by account {account}: {transaction:?}"
+                );
+                false
+            }
+            _ => true,
         }
     }
+
+    /// Queues a message to be sent to the peer
+    ///
+    /// Depending on the overflow policy, this might block until there is space in the queue.
+    pub async fn send(&self, msg: &Message<Operation>) -> Result<(), Error> {
+        let data = self.encoding.encode(msg)?;
+        self.outbound.push(data.into()).await
+    }
+
+    /// Queues a message without waiting for space in the queue (see `OutboundQueue::try_push`)
+    pub fn try_send(&self, msg: &Message<Operation>) -> Result<(), Error> {
+        let data = self.encoding.encode(msg)?;
+        self.outbound.try_push(data.into())
+    }
+
+    /// Queues part of the initial sync; this neither waits nor is subject to the queue size
+    pub fn send_sync(&self, msg: &Message<Operation>) -> Result<(), Error> {
+        let data = self.encoding.encode(msg)?;
+        self.outbound.push_unbounded(data.into())
+    }
+
+    /// Sends all queued messages to the peer and then closes the connection
+    pub async fn disconnect(&self) {
+        self.outbound.finish();
+        self.outbound.closed().await;
+    }
+
+    pub fn get_identifier(&self) -> u32 {
+        self.identifier
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.outbound.stats()
+    }
+}
+
+/// Writes queued messages to the socket until the queue is closed (or finished)
+async fn run_writer(
+    identifier: u32,
+    queue: Arc<OutboundQueue>,
+    mut framed: PeerWriteSocket,
+    bytes_sent: Arc<AtomicU64>,
+) {
+    while let Some(batch) = queue.pop_all().await {
+        for data in batch {
+            let len = data.len() as u64;
+
+            if let Err(err) = framed.feed(data).await {
+                log::error!("Failed to send data to peer {identifier}: {err}");
+                queue.close();
+                return;
+            }
+
+            bytes_sent.fetch_add(len, Ordering::Relaxed);
+        }
+
+        if let Err(err) = framed.flush().await {
+            log::error!("Failed to send data to peer {identifier}: {err}");
+            queue.close();
+            return;
+        }
+    }
+
+    // Lets the peer know that no more data will be sent
+    if let Err(err) = framed.close().await {
+        log::debug!("Failed to close connection to peer {identifier}: {err}");
+    }
+
+    queue.close();
 }
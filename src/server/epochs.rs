@@ -0,0 +1,217 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Weak;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::protocol::EpochId;
+use crate::server::capacity::EpochUsage;
+use crate::server::ledger_wrapper::LedgerWrapper;
+use crate::OpTrait;
+
+/// Decides when the current epoch ends and the next one starts
+///
+/// Can be parsed from strings like `interval:60`, `transactions:1000`, `bytes:1048576`,
+/// `poisson:600`, or `external` (durations are in seconds).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EpochTrigger {
+    /// After a fixed amount of time
+    Interval(Duration),
+    /// Once the epoch contains the given number of transactions
+    TransactionCount(usize),
+    /// Once the transactions of the epoch take up the given number of bytes
+    ByteSize(u64),
+    /// After exponentially distributed intervals with the given mean,
+    /// which mimics the arrival of proof-of-work key blocks
+    Poisson(Duration),
+    /// Only when a client sends `Message::TriggerEpoch`
+    External,
+}
+
+impl Default for EpochTrigger {
+    fn default() -> Self {
+        Self::Interval(Duration::from_secs(60))
+    }
+}
+
+impl EpochTrigger {
+    /// How long to wait until the next epoch (only for time-based triggers)
+    pub fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Self::Interval(length) => Some(*length),
+            Self::Poisson(mean) => {
+                // Inverse transform sampling; the sample must not be zero
+                let sample: f64 = 1.0 - rand::random::<f64>();
+                Some(mean.mul_f64(-sample.ln()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Does this trigger depend on the contents of the epoch rather than on time or clients?
+    pub fn is_size_based(&self) -> bool {
+        matches!(self, Self::TransactionCount(_) | Self::ByteSize(_))
+    }
+
+    /// Should the epoch end now that it has the given usage?
+    pub fn is_reached(&self, usage: &EpochUsage) -> bool {
+        match self {
+            Self::TransactionCount(count) => usage.transactions >= *count,
+            Self::ByteSize(size) => usage.bytes >= *size,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for EpochTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interval(length) => write!(f, "interval:{}", length.as_secs_f64()),
+            Self::TransactionCount(count) => write!(f, "transactions:{count}"),
+            Self::ByteSize(size) => write!(f, "bytes:{size}"),
+            Self::Poisson(mean) => write!(f, "poisson:{}", mean.as_secs_f64()),
+            Self::External => write!(f, "external"),
+        }
+    }
+}
+
+impl FromStr for EpochTrigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = match s.split_once(':') {
+            Some((kind, value)) => (kind, Some(value)),
+            None => (s, None),
+        };
+
+        let parse_secs = |value: Option<&str>| -> Result<Duration, String> {
+            let value = value.ok_or(format!("Epoch trigger '{kind}' needs a value"))?;
+            let secs: f64 = value
+                .parse()
+                .map_err(|_| format!("Invalid number of seconds: {value}"))?;
+
+            Duration::try_from_secs_f64(secs)
+                .ok()
+                .filter(|length| !length.is_zero())
+                .ok_or(format!("Epoch length must be positive: {value}"))
+        };
+
+        let parse_count = |value: Option<&str>| -> Result<u64, String> {
+            let value = value.ok_or(format!("Epoch trigger '{kind}' needs a value"))?;
+
+            match value.parse() {
+                Ok(0) | Err(_) => Err(format!("Invalid limit: {value}")),
+                Ok(count) => Ok(count),
+            }
+        };
+
+        match kind {
+            "interval" => Ok(Self::Interval(parse_secs(value)?)),
+            "poisson" => Ok(Self::Poisson(parse_secs(value)?)),
+            "transactions" => Ok(Self::TransactionCount(parse_count(value)? as usize)),
+            "bytes" => Ok(Self::ByteSize(parse_count(value)?)),
+            "external" if value.is_none() => Ok(Self::External),
+            _ => Err(format!("Unknown epoch trigger: {s}")),
+        }
+    }
+}
+
+/// Triggers are given as strings in configuration files
+impl<'de> Deserialize<'de> for EpochTrigger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Starts new epochs for time-based triggers until the ledger is dropped
+///
+/// The timer only starts once the first epoch was created and stops once the ledger is closed.
+pub(super) async fn run_epoch_timer<OpType: OpTrait + Serialize + DeserializeOwned>(
+    ledger: Weak<LedgerWrapper<OpType>>,
+    trigger: EpochTrigger,
+    mut first_epoch: watch::Receiver<EpochId>,
+    closed: CancellationToken,
+) {
+    tokio::select! {
+        result = first_epoch.changed() => {
+            if result.is_err() {
+                return;
+            }
+        }
+        _ = closed.cancelled() => return,
+    }
+
+    while let Some(delay) = trigger.next_delay() {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = closed.cancelled() => break,
+        }
+
+        let Some(ledger) = ledger.upgrade() else {
+            break;
+        };
+
+        if let Err(err) = ledger.start_new_epoch().await {
+            log::error!("Failed to start new epoch: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::EpochTrigger;
+    use crate::server::capacity::EpochUsage;
+
+    #[test]
+    fn parse() {
+        for trigger in [
+            EpochTrigger::Interval(Duration::from_millis(1500)),
+            EpochTrigger::TransactionCount(10),
+            EpochTrigger::ByteSize(4096),
+            EpochTrigger::Poisson(Duration::from_secs(600)),
+            EpochTrigger::External,
+        ] {
+            assert_eq!(trigger.to_string().parse(), Ok(trigger));
+        }
+
+        assert!("interval".parse::<EpochTrigger>().is_err());
+        assert!("transactions:0".parse::<EpochTrigger>().is_err());
+        assert!("poisson:-1".parse::<EpochTrigger>().is_err());
+        assert!("external:5".parse::<EpochTrigger>().is_err());
+    }
+
+    #[test]
+    fn poisson_mean() {
+        let trigger = EpochTrigger::Poisson(Duration::from_secs(10));
+        let num_samples = 100_000;
+
+        let total: Duration = (0..num_samples)
+            .map(|_| trigger.next_delay().unwrap())
+            .sum();
+        let mean = total.as_secs_f64() / num_samples as f64;
+
+        assert!((mean - 10.0).abs() < 0.5, "Mean was {mean}");
+    }
+
+    #[test]
+    fn size_triggers() {
+        let usage = EpochUsage {
+            transactions: 10,
+            bytes: 1000,
+            cost: 10,
+        };
+
+        assert!(EpochTrigger::TransactionCount(10).is_reached(&usage));
+        assert!(!EpochTrigger::TransactionCount(11).is_reached(&usage));
+        assert!(!EpochTrigger::ByteSize(1001).is_reached(&usage));
+        assert!(!EpochTrigger::default().is_reached(&usage));
+    }
+}
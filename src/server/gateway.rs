@@ -0,0 +1,430 @@
+//! HTTP and WebSocket access to the simulator for non-Rust clients
+//!
+//! * `POST /transactions` submits a JSON-encoded transaction
+//! * `GET /epochs` lists all epochs, `GET /epochs/{id}` returns a single epoch
+//! * `GET /transactions` returns all transactions (or those of one epoch with `?epoch={id}`)
+//! * `GET /accounts` lists all accounts that issued transactions
+//! * `GET /events` is a WebSocket streaming `NewEpochStarted` and `LedgerUpdate` messages
+//! * `GET /metrics` returns the server metrics in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::routing::get;
+use axum::{Json, Router};
+
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use log::{debug, info, warn};
+
+use crate::protocol::{EpochId, RejectReason};
+use crate::server::ledger_wrapper::LedgerWrapper;
+use crate::server::trace::TraceEvent;
+use crate::server::Callback;
+use crate::transactions::Transaction;
+use crate::{AccountId, Epoch, Error, OpTrait};
+
+pub const DEFAULT_GATEWAY_PORT: u16 = 8081;
+
+struct GatewayState<OpType: OpTrait> {
+    ledger: Arc<LedgerWrapper<OpType>>,
+    callback: Arc<dyn Callback<OpType>>,
+    /// Cancelled when the server shuts down
+    stop: CancellationToken,
+}
+
+// derive(Clone) would require OpType: Clone
+impl<OpType: OpTrait> Clone for GatewayState<OpType> {
+    fn clone(&self) -> Self {
+        Self {
+            ledger: self.ledger.clone(),
+            callback: self.callback.clone(),
+            stop: self.stop.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EpochSummary {
+    identifier: EpochId,
+    timestamp: i64,
+    num_transactions: usize,
+    byte_size: u64,
+    total_cost: u64,
+    end_timestamp: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AccountSummary {
+    account: AccountId,
+    num_transactions: usize,
+}
+
+#[derive(Deserialize)]
+struct TransactionQuery {
+    epoch: Option<EpochId>,
+}
+
+pub async fn run_gateway<OpType: OpTrait + Serialize + DeserializeOwned>(
+    addr: SocketAddr,
+    ledger: Arc<LedgerWrapper<OpType>>,
+    callback: Arc<dyn Callback<OpType>>,
+    stop: CancellationToken,
+) -> Result<(), Error> {
+    let state = GatewayState {
+        ledger,
+        callback,
+        stop: stop.clone(),
+    };
+
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving HTTP gateway on {addr:?}");
+
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(async move { stop.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+fn router<OpType: OpTrait + Serialize + DeserializeOwned>(state: GatewayState<OpType>) -> Router {
+    Router::new()
+        .route(
+            "/transactions",
+            get(get_transactions::<OpType>).post(post_transaction::<OpType>),
+        )
+        .route("/epochs", get(get_epochs::<OpType>))
+        .route("/epochs/{identifier}", get(get_epoch::<OpType>))
+        .route("/accounts", get(get_accounts::<OpType>))
+        .route("/events", get(subscribe_events::<OpType>))
+        .route("/metrics", get(get_metrics::<OpType>))
+        .with_state(state)
+}
+
+async fn post_transaction<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+    Json(transaction): Json<Transaction<OpType>>,
+) -> StatusCode {
+    let trace = state.ledger.trace();
+    let id = transaction.get_id();
+
+    trace.record(TraceEvent::transaction_received(&transaction, None));
+
+    let valid = state.callback.validate_transaction(&transaction);
+    trace.record(TraceEvent::TransactionValidated {
+        transaction: id,
+        valid,
+    });
+
+    if !valid {
+        debug!("Discarded transaction because validation failed: {transaction:?}");
+        state
+            .ledger
+            .metrics()
+            .transactions_discarded
+            .fetch_add(1, Ordering::Relaxed);
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+
+    state.callback.notify_new_transaction(&transaction);
+
+    match state.ledger.insert(transaction).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(Error::Rejected(RejectReason::ExceedsEpochCapacity)) => StatusCode::PAYLOAD_TOO_LARGE,
+        Err(Error::Rejected(reason)) => {
+            debug!("Dropped transaction from gateway: {reason}");
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        Err(err) => {
+            warn!("Failed to insert transaction from gateway: {err}");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn get_epochs<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+) -> Json<Vec<EpochSummary>> {
+    let summaries = all_epochs(&state.ledger)
+        .map(|(identifier, epoch)| EpochSummary {
+            identifier,
+            timestamp: epoch.get_timestamp(),
+            num_transactions: epoch.size(),
+            byte_size: epoch.byte_size(),
+            total_cost: epoch.total_cost(),
+            end_timestamp: epoch.get_statistics().map(|s| s.end_timestamp),
+        })
+        .collect();
+
+    Json(summaries)
+}
+
+async fn get_epoch<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+    Path(identifier): Path<EpochId>,
+) -> Result<Json<Epoch<OpType>>, StatusCode> {
+    match state.ledger.get_epoch(identifier) {
+        Ok(epoch) => Ok(Json(epoch)),
+        Err(_) => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn get_transactions<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+    Query(query): Query<TransactionQuery>,
+) -> Result<Json<Vec<Transaction<OpType>>>, StatusCode> {
+    if let Some(identifier) = query.epoch {
+        let epoch = state
+            .ledger
+            .get_epoch(identifier)
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+
+        return Ok(Json(epoch.get_transactions().clone()));
+    }
+
+    let transactions = all_epochs(&state.ledger)
+        .flat_map(|(_, epoch)| epoch.get_transactions().clone())
+        .collect();
+
+    Ok(Json(transactions))
+}
+
+async fn get_metrics<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+) -> String {
+    state.ledger.metrics().render()
+}
+
+async fn get_accounts<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+) -> Json<Vec<AccountSummary>> {
+    let mut counts = BTreeMap::<AccountId, usize>::new();
+
+    for (_, epoch) in all_epochs(&state.ledger) {
+        for tx in epoch.get_transactions() {
+            *counts.entry(*tx.get_source()).or_default() += 1;
+        }
+    }
+
+    let accounts = counts
+        .into_iter()
+        .map(|(account, num_transactions)| AccountSummary {
+            account,
+            num_transactions,
+        })
+        .collect();
+
+    Json(accounts)
+}
+
+async fn subscribe_events<OpType: OpTrait + Serialize + DeserializeOwned>(
+    State(state): State<GatewayState<OpType>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| forward_events(state.ledger, socket, state.stop))
+}
+
+async fn forward_events<OpType: OpTrait + Serialize + DeserializeOwned>(
+    ledger: Arc<LedgerWrapper<OpType>>,
+    mut socket: WebSocket,
+    stop: CancellationToken,
+) {
+    let mut events = ledger.subscribe_events();
+
+    loop {
+        let result = tokio::select! {
+            result = events.recv() => result,
+            _ = stop.cancelled() => {
+                // Events that were already broadcast are still forwarded
+                match events.try_recv() {
+                    Ok(msg) => Ok(msg),
+                    Err(_) => break,
+                }
+            }
+        };
+
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(num)) => {
+                warn!("WebSocket subscriber missed {num} events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let text = match serde_json::to_string(&msg) {
+            Ok(text) => text,
+            Err(err) => {
+                warn!("Failed to serialize event: {err}");
+                continue;
+            }
+        };
+
+        if socket.send(WsMessage::Text(text.into())).await.is_err() {
+            debug!("WebSocket subscriber disconnected");
+            return;
+        }
+    }
+
+    let _ = socket.send(WsMessage::Close(None)).await;
+}
+
+/// Iterates over copies of all epochs in order
+fn all_epochs<OpType: OpTrait + Serialize + DeserializeOwned>(
+    ledger: &LedgerWrapper<OpType>,
+) -> impl Iterator<Item = (EpochId, Epoch<OpType>)> + '_ {
+    (0..ledger.num_epochs() as EpochId)
+        .filter_map(|identifier| Some((identifier, ledger.get_epoch(identifier).ok()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Request, StatusCode};
+    use axum::Router;
+
+    use serde_json::Value;
+
+    use tokio_util::sync::CancellationToken;
+    use tower::ServiceExt;
+
+    use super::{router, GatewayState};
+    use crate::server::ledger_wrapper::LedgerWrapper;
+    use crate::server::Callback;
+    use crate::{generate_key_pair, to_account_id, TestOperation, Transaction};
+
+    /// Only accepts transactions of a single account
+    struct SingleAccount(u64);
+
+    impl Callback<TestOperation> for SingleAccount {
+        fn validate_transaction(&self, tx: &Transaction<TestOperation>) -> bool {
+            *tx.get_source() == self.0
+        }
+
+        fn notify_new_transaction(&self, _: &Transaction<TestOperation>) {}
+    }
+
+    async fn setup(
+        callback: Arc<dyn Callback<TestOperation>>,
+    ) -> (Arc<LedgerWrapper<TestOperation>>, Router) {
+        let ledger = LedgerWrapper::<TestOperation>::new(
+            10_000.0,
+            10,
+            0,
+            Default::default(),
+            Default::default(),
+        );
+        ledger.start_new_epoch().await.unwrap();
+
+        let state = GatewayState {
+            ledger: ledger.clone(),
+            callback,
+            stop: CancellationToken::new(),
+        };
+
+        (ledger, router(state))
+    }
+
+    async fn post(router: &Router, body: String) -> StatusCode {
+        let request = Request::post("/transactions")
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    async fn get(router: &Router, uri: &str) -> (StatusCode, Vec<u8>) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn get_json(router: &Router, uri: &str) -> Value {
+        let (status, body) = get(router, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn submit_and_query() {
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let (ledger, router) = setup(Arc::new(SingleAccount(account))).await;
+
+        for _ in 0..2 {
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            let status = post(&router, serde_json::to_string(&tx).unwrap()).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+
+        let (other_key, other_public_key) = generate_key_pair();
+        let other = to_account_id(&other_public_key);
+        let tx = Transaction::new(other, TestOperation::Empty {}, other_key);
+        let status = post(&router, serde_json::to_string(&tx).unwrap()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert!(post(&router, "{}".to_string()).await.is_client_error());
+
+        ledger.start_new_epoch().await.unwrap();
+
+        let epochs = get_json(&router, "/epochs").await;
+        assert_eq!(epochs.as_array().unwrap().len(), 2);
+        assert_eq!(epochs[0]["num_transactions"], 2);
+        assert!(epochs[0]["end_timestamp"].is_number());
+        assert!(epochs[1]["end_timestamp"].is_null());
+
+        let epoch = get_json(&router, "/epochs/0").await;
+        assert!(epoch.is_object());
+        assert_eq!(get(&router, "/epochs/7").await.0, StatusCode::NOT_FOUND);
+
+        let transactions = get_json(&router, "/transactions").await;
+        assert_eq!(transactions.as_array().unwrap().len(), 2);
+        let transactions = get_json(&router, "/transactions?epoch=1").await;
+        assert_eq!(transactions.as_array().unwrap().len(), 0);
+        assert_eq!(
+            get(&router, "/transactions?epoch=7").await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let accounts = get_json(&router, "/accounts").await;
+        assert_eq!(accounts[0]["account"], account);
+        assert_eq!(accounts[0]["num_transactions"], 2);
+
+        let (status, metrics) = get(&router, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(metrics.contains("transactions_accepted"));
+        assert!(metrics.contains("blocksim_transactions_discarded_total 1\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_transaction() {
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let (ledger, router) = setup(Arc::new(SingleAccount(account + 1))).await;
+
+        let tx = Transaction::new(account, TestOperation::Empty {}, private_key);
+        let status = post(&router, serde_json::to_string(&tx).unwrap()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(ledger.get_epoch(0).unwrap().size(), 0);
+    }
+}
@@ -1,76 +1,263 @@
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use tokio::spawn;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
 
-use crate::protocol::{EpochId, Message};
+use crate::protocol::{EpochId, Message, RejectReason};
+use crate::server::admission::{run_admission, AdmissionRequest, RateLimiter};
+use crate::server::capacity::{EpochCapacity, EpochUsage};
 use crate::server::connection::PeerConnection;
-use crate::transactions::Transaction;
-use crate::{Epoch, Ledger, OpTrait};
+use crate::server::epochs::{run_epoch_timer, EpochTrigger};
+use crate::server::metrics::Metrics;
+use crate::server::outbound::QueueStats;
+use crate::server::storage::EpochStorage;
+use crate::server::trace::{EventTrace, TraceEvent};
+use crate::transactions::{Transaction, TxPayload};
+use crate::{Epoch, Error, Ledger, OpTrait, SharedStateMachine};
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use log::*;
 
+type PeerMap<OpType> = HashMap<u32, Arc<PeerConnection<OpType>>>;
+
+/// A message that is sent to all peers connected at the time it was created
+struct Broadcast<OpType: OpTrait> {
+    deliver_at: Instant,
+    msg: Message<OpType>,
+    peers: PeerMap<OpType>,
+}
+
+enum BroadcastTask<OpType: OpTrait> {
+    Deliver(Box<Broadcast<OpType>>),
+    /// Notifies the sender once all earlier broadcasts have been delivered
+    Flush(oneshot::Sender<()>),
+}
+
+enum StorageTask<OpType: OpTrait> {
+    Append(EpochId, Epoch<OpType>),
+    /// Notifies the sender once all earlier epochs have been written
+    Flush(oneshot::Sender<()>),
+}
+
 /// This adds some server-side functionality to the ledger class
 pub struct LedgerWrapper<OpType: OpTrait> {
     ledger: Arc<Ledger<OpType>>,
-    peers: Mutex<HashMap<u32, Arc<PeerConnection<OpType>>>>,
-    min_interval: Duration,
+    peers: Mutex<PeerMap<OpType>>,
     latency: Duration,
-    last_tx: Mutex<Instant>,
+    admission: mpsc::UnboundedSender<AdmissionRequest<OpType>>,
+    broadcasts: mpsc::UnboundedSender<BroadcastTask<OpType>>,
     next_epoch_id: AtomicU32,
+    next_sequence: AtomicU64,
+    events: broadcast::Sender<Message<OpType>>,
+    state_machine: std::sync::Mutex<Option<SharedStateMachine<OpType>>>,
+    capacity: EpochCapacity,
+    /// Only modified while holding the peer lock
+    usage: std::sync::Mutex<EpochUsage>,
+    /// The identifier of the most recent epoch
+    epochs: watch::Sender<EpochId>,
+    /// The number of transactions committed so far
+    committed: watch::Sender<usize>,
+    epoch_trigger: EpochTrigger,
+    /// Sealed epochs are written by a separate task, so that file IO does not hold the peer lock
+    storage: std::sync::Mutex<Option<mpsc::UnboundedSender<StorageTask<OpType>>>>,
+    /// Cancelled (while holding the peer lock) once the server shuts down
+    closed: CancellationToken,
+    metrics: Arc<Metrics>,
+    trace: Arc<EventTrace>,
 }
 
+/// How many events a slow subscriber may fall behind before it misses some
+const EVENT_QUEUE_SIZE: usize = 10_000;
+
 impl<OpType: OpTrait + Serialize + DeserializeOwned> LedgerWrapper<OpType> {
-    pub fn new(throughput: f64, latency_ms: u32) -> Self {
+    /// Creates the ledger and starts its admission task
+    ///
+    /// At most `burst` transactions are admitted at once, while the long-term rate is limited
+    /// to `throughput` transactions per second. Additionally, no epoch will exceed `capacity`.
+    ///
+    /// Time-based epoch triggers only take effect once the first epoch has been started.
+    pub fn new(
+        throughput: f64,
+        burst: u32,
+        latency_ms: u32,
+        capacity: EpochCapacity,
+        epoch_trigger: EpochTrigger,
+    ) -> Arc<Self> {
         let ledger = Arc::new(Ledger::default());
         let peers = Mutex::new(HashMap::new());
 
-        let min_interval = Duration::from_secs_f64(1.0 / throughput);
+        // Validated by `ServerConfigBuilder::build`
+        let limiter = RateLimiter::new(throughput, burst).expect("Invalid rate limit");
         let latency = Duration::from_millis(latency_ms.into());
 
-        let last_tx = Mutex::new(Instant::now());
-
         let next_epoch_id = AtomicU32::new(0);
+        let next_sequence = AtomicU64::new(0);
+        let (events, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        let (admission, requests) = mpsc::unbounded_channel();
+        let (broadcasts, pending) = mpsc::unbounded_channel();
+        let (epochs, _) = watch::channel(0);
+        let (committed, _) = watch::channel(0);
+        let closed = CancellationToken::new();
+        let trace = Arc::new(EventTrace::default());
 
-        Self {
-            ledger,
-            peers,
-            min_interval,
-            latency,
-            last_tx,
-            next_epoch_id,
-        }
+        spawn(run_broadcasts(pending, events.clone(), trace.clone()));
+
+        Arc::new_cyclic(|weak_self| {
+            spawn(run_admission(
+                weak_self.clone(),
+                limiter,
+                requests,
+                closed.clone(),
+            ));
+
+            if epoch_trigger.next_delay().is_some() {
+                let first_epoch = epochs.subscribe();
+                spawn(run_epoch_timer(
+                    weak_self.clone(),
+                    epoch_trigger,
+                    first_epoch,
+                    closed.clone(),
+                ));
+            }
+
+            Self {
+                ledger,
+                peers,
+                latency,
+                admission,
+                broadcasts,
+                next_epoch_id,
+                next_sequence,
+                events,
+                state_machine: Default::default(),
+                capacity,
+                usage: Default::default(),
+                epochs,
+                committed,
+                epoch_trigger,
+                storage: Default::default(),
+                closed,
+                metrics: Default::default(),
+                trace,
+            }
+        })
+    }
+
+    /// Get notified about all messages broadcast to peers (except the initial sync)
+    #[allow(dead_code)]
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Message<OpType>> {
+        self.events.subscribe()
     }
 
-    pub async fn register_peer(&self, identifier: u32, peer: Arc<PeerConnection<OpType>>) {
+    pub async fn register_peer(
+        &self,
+        identifier: u32,
+        peer: Arc<PeerConnection<OpType>>,
+    ) -> Result<(), Error> {
         // Hold lock throughout function to avoid sending messages twicey
         let mut peers = self.peers.lock().await;
 
+        if self.closed.is_cancelled() {
+            return Err(Error::ServerShutdown);
+        }
+
         // First send all previous transactions / epochs
         let num_epochs = self.ledger.num_epochs();
         for i in 0..num_epochs {
             let eid = i as EpochId;
-            let epoch = self.ledger.get_epoch(eid);
+            let epoch = self.ledger.get_epoch(eid)?;
             let msg = Message::SyncEpoch {
                 identifier: eid,
                 epoch,
             };
 
-            peer.send(&msg).await;
+            // Must not wait for the peer while holding the lock
+            peer.send_sync(&msg)?;
         }
 
         peers.insert(identifier, peer);
+        self.metrics.connected_peers.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
     }
 
     pub async fn unregister_peer(&self, identifier: u32) {
-        self.peers.lock().await.remove(&identifier);
+        if self.peers.lock().await.remove(&identifier).is_some() {
+            self.metrics.connected_peers.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        self.metrics.remove_peer(identifier);
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Events are only recorded once a writer has been set using `EventTrace::start`
+    pub fn trace(&self) -> &EventTrace {
+        &self.trace
+    }
+
+    /// Returns the state of the outbound queue of each connected peer
+    pub async fn get_queue_stats(&self) -> Vec<(u32, QueueStats)> {
+        let peers = self.peers.lock().await;
+
+        peers
+            .iter()
+            .map(|(identifier, peer)| (*identifier, peer.queue_stats()))
+            .collect()
+    }
+
+    /// Derives application state from all committed transactions
+    ///
+    /// The state machine must be in its initial state; transactions that are
+    /// already in the ledger will be applied to it first.
+    pub async fn set_state_machine(&self, state_machine: SharedStateMachine<OpType>) {
+        // Prevent concurrent commits
+        let _peers = self.peers.lock().await;
+
+        {
+            let mut state_machine = state_machine.lock().unwrap();
+
+            for identifier in 0..self.ledger.num_epochs() as EpochId {
+                let epoch = self.ledger.get_epoch(identifier).unwrap();
+                state_machine.replay_epoch(&epoch);
+            }
+        }
+
+        *self.state_machine.lock().unwrap() = Some(state_machine);
+    }
+
+    /// Writes every epoch to the given storage once it is sealed
+    pub fn set_storage(&self, storage: EpochStorage) {
+        let (sender, pending) = mpsc::unbounded_channel();
+        spawn(run_storage(storage, pending));
+
+        *self.storage.lock().unwrap() = Some(sender);
+    }
+
+    /// Hash of the application state (if there is a state machine)
+    #[allow(dead_code)]
+    pub fn state_digest(&self) -> Option<Vec<u8>> {
+        let state_machine = self.state_machine.lock().unwrap();
+        state_machine.as_ref().map(|sm| sm.lock().unwrap().digest())
+    }
+
+    /// Get notified whenever a new epoch starts
+    pub fn subscribe_epochs(&self) -> watch::Receiver<EpochId> {
+        self.epochs.subscribe()
+    }
+
+    /// Get notified whenever a transaction is committed (before it is sent to peers)
+    pub fn subscribe_committed(&self) -> watch::Receiver<usize> {
+        self.committed.subscribe()
     }
 
     #[allow(dead_code)]
@@ -79,12 +266,95 @@ impl<OpType: OpTrait + Serialize + DeserializeOwned> LedgerWrapper<OpType> {
     }
 
     #[allow(dead_code)]
-    pub fn get_epoch(&self, identifier: EpochId) -> Epoch<OpType> {
+    pub fn get_epoch(&self, identifier: EpochId) -> Result<Epoch<OpType>, Error> {
         self.ledger.get_epoch(identifier)
     }
 
-    pub async fn start_new_epoch(&self) {
-        let identifier = self.next_epoch_id.fetch_add(1, Ordering::SeqCst);
+    pub fn get_epoch_trigger(&self) -> EpochTrigger {
+        self.epoch_trigger
+    }
+
+    /// Ends the current epoch (if any) and starts a new one
+    pub async fn start_new_epoch(&self) -> Result<(), Error> {
+        for (peer_id, stats) in self.get_queue_stats().await {
+            debug!(
+                "Outbound queue of peer {peer_id}: depth={} max_depth={} dropped={}",
+                stats.depth, stats.max_depth, stats.dropped
+            );
+        }
+
+        // Lock peers before ledger
+        let peers = self.peers.lock().await;
+
+        if self.closed.is_cancelled() {
+            return Err(Error::ServerShutdown);
+        }
+
+        self.open_epoch(&peers)
+    }
+
+    /// Stops admitting transactions and disconnects all peers
+    ///
+    /// The current epoch is sealed (and written to storage, if any). Peers receive all
+    /// pending broadcasts, including the `EpochSealed` message, before their connection is closed.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let peers = {
+            let mut peers = self.peers.lock().await;
+
+            if self.closed.is_cancelled() {
+                return Err(Error::ServerShutdown);
+            }
+
+            self.closed.cancel();
+
+            if self.ledger.num_epochs() > 0 {
+                let identifier = self.ledger.get_current_epoch();
+                let timestamp = chrono::offset::Utc::now().timestamp();
+                self.seal_epoch(identifier, timestamp, &peers)?;
+            }
+
+            self.metrics
+                .connected_peers
+                .fetch_sub(peers.len() as i64, Ordering::Relaxed);
+
+            std::mem::take(&mut *peers)
+        };
+
+        let (flushed, done) = oneshot::channel();
+        if self.broadcasts.send(BroadcastTask::Flush(flushed)).is_ok() {
+            let _ = done.await;
+        }
+
+        let storage = self.storage.lock().unwrap().clone();
+        if let Some(storage) = storage {
+            let (flushed, done) = oneshot::channel();
+            if storage.send(StorageTask::Flush(flushed)).is_ok() {
+                let _ = done.await;
+            }
+        }
+
+        for peer in peers.values() {
+            peer.disconnect().await;
+        }
+
+        self.trace.flush();
+
+        info!(
+            "Ledger shut down after disconnecting {} peer(s)",
+            peers.len()
+        );
+        Ok(())
+    }
+
+    /// Must be called while holding the peer lock, so no transaction is committed concurrently
+    fn open_epoch(&self, peers: &PeerMap<OpType>) -> Result<(), Error> {
+        // Only allocated once the epoch exists, so that a failure does not leave a gap
+        let identifier = self.next_epoch_id.load(Ordering::SeqCst);
+
+        // Check before sealing, so the previous epoch stays open if this fails
+        if self.ledger.get_epoch_timestamp(identifier).is_ok() {
+            return Err(Error::EpochAlreadyExists(identifier));
+        }
 
         let now = chrono::offset::Utc::now();
         let timestamp = now.timestamp();
@@ -94,65 +364,869 @@ impl<OpType: OpTrait + Serialize + DeserializeOwned> LedgerWrapper<OpType> {
             identifier, timestamp
         );
 
+        if identifier > 0 {
+            self.seal_epoch(identifier - 1, timestamp, peers)?;
+        }
+
+        self.ledger.create_new_epoch(identifier, timestamp)?;
+        self.next_epoch_id.store(identifier + 1, Ordering::SeqCst);
+        *self.usage.lock().unwrap() = EpochUsage::default();
+
+        self.epochs.send_replace(identifier);
+
+        // Do not delay this, but make sure it is not delivered before earlier updates
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        self.trace.record(TraceEvent::EpochStarted {
+            epoch: identifier,
+            sequence,
+        });
+
+        let msg = Message::NewEpochStarted {
+            sequence,
+            identifier,
+            timestamp,
+        };
+        self.queue_broadcast(Instant::now(), msg, peers);
+
+        Ok(())
+    }
+
+    /// Must be called while holding the peer lock, so no transaction is committed concurrently
+    fn seal_epoch(
+        &self,
+        identifier: EpochId,
+        end_timestamp: i64,
+        peers: &PeerMap<OpType>,
+    ) -> Result<(), Error> {
+        let statistics = self.ledger.seal_epoch(identifier, end_timestamp)?;
+
+        info!(
+            "Sealed epoch {identifier} with {} transactions ({} bytes, total cost of {}) from {} accounts",
+            statistics.num_transactions,
+            statistics.byte_size,
+            statistics.total_cost,
+            statistics.transactions_per_account.len()
+        );
+
+        self.metrics
+            .epoch_transactions
+            .observe(statistics.num_transactions as f64);
+        self.metrics
+            .epoch_bytes
+            .observe(statistics.byte_size as f64);
+
+        if let Some(storage) = &*self.storage.lock().unwrap() {
+            let epoch = self.ledger.get_epoch(identifier)?;
+
+            if storage
+                .send(StorageTask::Append(identifier, epoch))
+                .is_err()
+            {
+                error!("Storage task is not running");
+            }
+        }
+
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        self.trace.record(TraceEvent::EpochSealed {
+            epoch: identifier,
+            sequence,
+            num_transactions: statistics.num_transactions,
+        });
+
+        let msg = Message::EpochSealed {
+            sequence,
+            identifier,
+            statistics,
+        };
+        self.queue_broadcast(Instant::now(), msg, peers);
+
+        Ok(())
+    }
+
+    /// Submits a transaction and waits until it has been added to the ledger
+    ///
+    /// Transactions are admitted in the order this function was called. Fails with
+    /// `Error::Rejected` if the transaction was dropped (e.g., because it expired).
+    pub async fn insert(&self, transaction: Transaction<OpType>) -> Result<(), Error> {
+        self.insert_batch(vec![transaction])
+            .await
+            .pop()
+            .expect("Got no result for transaction")
+    }
+
+    /// Submits multiple transactions and waits until all of them have been processed
+    ///
+    /// The batch is handed to the admission task at once, so its transactions are admitted
+    /// in order and without interleaving with those of other submitters. Each of them is still
+    /// subject to the throughput limit. Returns one result per transaction (see `insert`).
+    pub async fn insert_batch(
+        &self,
+        transactions: Vec<Transaction<OpType>>,
+    ) -> Vec<Result<(), Error>> {
+        self.metrics
+            .transactions_submitted
+            .fetch_add(transactions.len() as u64, Ordering::Relaxed);
+
+        let ids: Vec<_> = transactions.iter().map(|tx| tx.get_id()).collect();
+        let results = self.admit(transactions).await;
+
+        for (id, result) in ids.into_iter().zip(&results) {
+            match result {
+                Ok(()) => {
+                    self.metrics
+                        .transactions_accepted
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Err(Error::Rejected(reason)) => {
+                    self.metrics.record_rejection(*reason);
+                    self.trace.record(TraceEvent::TransactionRejected {
+                        transaction: id,
+                        reason: *reason,
+                    });
+                }
+                Err(_) => {}
+            }
+        }
+
+        results
+    }
+
+    async fn admit(&self, transactions: Vec<Transaction<OpType>>) -> Vec<Result<(), Error>> {
+        let num_transactions = transactions.len();
+
+        // Transactions that fail these checks are never sent to the admission task
+        let mut early_results = Vec::with_capacity(num_transactions);
+        let mut admitted = Vec::with_capacity(num_transactions);
+
+        for transaction in transactions {
+            match self.check_admissible(&transaction) {
+                Ok(()) => {
+                    early_results.push(None);
+                    admitted.push(transaction);
+                }
+                Err(err) => early_results.push(Some(Err(err))),
+            }
+        }
+
+        let mut results = if admitted.is_empty() {
+            vec![]
+        } else {
+            self.request_admission(admitted).await
+        }
+        .into_iter();
+
+        early_results
+            .into_iter()
+            .map(|early| {
+                early.unwrap_or_else(|| results.next().unwrap_or(Err(Error::ServerShutdown)))
+            })
+            .collect()
+    }
+
+    fn check_admissible(&self, transaction: &Transaction<OpType>) -> Result<(), Error> {
+        if !self.capacity.fits_empty(transaction) {
+            return Err(Error::Rejected(RejectReason::ExceedsEpochCapacity));
+        }
+
+        self.check_validity(transaction)?;
+
+        if self.closed.is_cancelled() {
+            return Err(Error::ServerShutdown);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the admission task to process the transactions
+    ///
+    /// Returns fewer results than transactions if the server shut down in the meantime.
+    async fn request_admission(
+        &self,
+        transactions: Vec<Transaction<OpType>>,
+    ) -> Vec<Result<(), Error>> {
+        let (results, receiver) = oneshot::channel();
+        let request = AdmissionRequest {
+            transactions,
+            arrival: Instant::now(),
+            results,
+        };
+
+        if self.admission.send(request).is_err() {
+            return vec![];
+        }
+
+        receiver.await.unwrap_or_default()
+    }
+
+    /// Adds an admitted transaction to the ledger and broadcasts it
+    ///
+    /// Returns false, without modifying the ledger, if the current epoch is at capacity.
+    pub(super) async fn commit(&self, transaction: &Transaction<OpType>) -> Result<bool, Error> {
         // Lock peers before ledger
         let peers = self.peers.lock().await;
-        self.ledger.create_new_epoch(identifier, timestamp);
-        let peers = peers.clone();
 
-        spawn(async move {
-            let msg = Message::NewEpochStarted {
-                identifier,
-                timestamp,
-            };
-            let mut futures = Vec::new();
+        if self.closed.is_cancelled() {
+            return Err(Error::ServerShutdown);
+        }
+
+        // The transaction might have expired while waiting for admission
+        self.check_validity(transaction)?;
+
+        // Checked under the lock, as the policy of the source might have changed meanwhile
+        self.check_authorization(transaction)?;
 
-            // broadcast
-            for peer in peers.values() {
-                futures.push(peer.send(&msg));
+        let mut usage = self.usage.lock().unwrap();
+        if !self.capacity.fits(&usage, transaction) {
+            // A size-based trigger might never fire once the epoch is at capacity, so nothing
+            // else would start the next epoch
+            if self.epoch_trigger.is_size_based() && usage.transactions > 0 {
+                drop(usage);
+                self.open_epoch(&peers)?;
             }
 
-            for future in futures.drain(..) {
-                future.await;
+            // Retried once the (new) epoch has started, which checks the transaction again
+            return Ok(false);
+        }
+
+        self.ledger.insert(transaction.clone())?;
+        usage.add(transaction);
+
+        let epoch_full = self.epoch_trigger.is_reached(&usage);
+        drop(usage);
+
+        if let Some(state_machine) = &*self.state_machine.lock().unwrap() {
+            // Invalid transactions are part of the ledger but do not change the state
+            if let Err(err) = state_machine.lock().unwrap().apply(transaction) {
+                debug!("Transaction did not change application state: {err}");
             }
+        }
+
+        // Only notify once the state reflects the transaction
+        self.committed.send_modify(|num| *num += 1);
+
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        self.trace.record(TraceEvent::TransactionCommitted {
+            transaction: transaction.get_id(),
+            epoch: *self.epochs.borrow(),
+            sequence,
         });
+
+        let msg = Message::LedgerUpdate {
+            sequence,
+            transaction: transaction.clone(),
+        };
+        self.queue_broadcast(Instant::now() + self.latency, msg, &peers);
+
+        // Size-based triggers start the next epoch right away, so no other transaction gets in
+        if epoch_full {
+            if let Err(err) = self.open_epoch(&peers) {
+                error!("Failed to start new epoch: {err}");
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Can the transaction be committed to the current epoch right now?
+    fn check_validity(&self, transaction: &Transaction<OpType>) -> Result<(), Error> {
+        let epoch = *self.epochs.borrow();
+        let now = chrono::offset::Utc::now().timestamp();
+
+        transaction
+            .get_validity()
+            .check(epoch, now)
+            .map_err(Error::Rejected)
     }
 
-    pub async fn insert(&self, transaction: Transaction<OpType>) {
+    /// Transactions of accounts that were created in the ledger must be authorized by them
+    ///
+    /// Other accounts are not checked, so that clients do not have to create an account first.
+    fn check_authorization(&self, transaction: &Transaction<OpType>) -> Result<(), Error> {
+        let is_plain_operation = matches!(transaction.get_payload(), TxPayload::Operation { .. })
+            && !transaction.is_multisig();
+
+        if is_plain_operation && !self.ledger.has_account(transaction.get_source()) {
+            return Ok(());
+        }
+
+        self.ledger.authorize(transaction).map_err(|err| {
+            debug!("Transaction is not authorized: {err}");
+            Error::Rejected(RejectReason::Unauthorized)
+        })
+    }
+
+    /// Must be called while holding the peer lock so that broadcasts are queued in sequence order
+    fn queue_broadcast(&self, deliver_at: Instant, msg: Message<OpType>, peers: &PeerMap<OpType>) {
+        let broadcast = Broadcast {
+            deliver_at,
+            msg,
+            peers: peers.clone(),
+        };
+
+        if self
+            .broadcasts
+            .send(BroadcastTask::Deliver(Box::new(broadcast)))
+            .is_err()
         {
-            let mut last_tx = self.last_tx.lock().await;
-            let now = Instant::now();
-            let diff = now.duration_since(*last_tx);
+            error!("Broadcast task is not running");
+        }
+    }
+}
 
-            if diff < self.min_interval {
-                sleep(self.min_interval - diff).await;
+/// Delivers broadcasts one at a time in the order they were queued
+///
+/// A broadcast that is due earlier than its predecessor (e.g., a new epoch that is not subject
+/// to latency) waits for it, so peers always see updates in the same order as the ledger.
+async fn run_broadcasts<OpType: OpTrait + Serialize + DeserializeOwned>(
+    mut pending: mpsc::UnboundedReceiver<BroadcastTask<OpType>>,
+    events: broadcast::Sender<Message<OpType>>,
+    trace: Arc<EventTrace>,
+) {
+    while let Some(task) = pending.recv().await {
+        let broadcast = match task {
+            BroadcastTask::Deliver(broadcast) => broadcast,
+            BroadcastTask::Flush(flushed) => {
+                let _ = flushed.send(());
+                continue;
             }
+        };
+
+        sleep_until(broadcast.deliver_at).await;
+
+        trace!("Broadcasting ledger update: {:?}", broadcast.msg);
+
+        // Fails only if nobody is subscribed
+        let _ = events.send(broadcast.msg.clone());
 
-            *last_tx = now;
+        let sequence = match &broadcast.msg {
+            Message::NewEpochStarted { sequence, .. }
+            | Message::EpochSealed { sequence, .. }
+            | Message::LedgerUpdate { sequence, .. } => Some(*sequence),
+            _ => None,
+        };
+
+        for peer in broadcast.peers.values() {
+            if broadcast_to(peer, &broadcast.msg) {
+                if let Some(sequence) = sequence {
+                    trace.record(TraceEvent::BroadcastQueued {
+                        peer: peer.get_identifier(),
+                        sequence,
+                    });
+                }
+            }
         }
+    }
+}
 
-        let latency = self.latency;
+/// Writes sealed epochs in the order they were sealed
+///
+/// The file is written on a blocking thread, so a slow disk does not stall a worker thread.
+async fn run_storage<OpType: OpTrait + Serialize + DeserializeOwned>(
+    mut storage: EpochStorage,
+    mut pending: mpsc::UnboundedReceiver<StorageTask<OpType>>,
+) {
+    while let Some(task) = pending.recv().await {
+        let (identifier, epoch) = match task {
+            StorageTask::Append(identifier, epoch) => (identifier, epoch),
+            StorageTask::Flush(flushed) => {
+                let _ = flushed.send(());
+                continue;
+            }
+        };
 
-        // Lock peers before ledger
-        let peers = self.peers.lock().await;
-        self.ledger.insert(transaction.clone());
-        let peers = peers.clone();
+        let result;
+        (storage, result) = tokio::task::spawn_blocking(move || {
+            let result = storage.append(identifier, &epoch);
+            (storage, result)
+        })
+        .await
+        .expect("Storage writer panicked");
+
+        if let Err(err) = result {
+            error!("Failed to store epoch {identifier}: {err}");
+        }
+    }
+}
+
+/// Sends a message to a single peer as part of a broadcast
+///
+/// This never waits for the peer, so a slow peer does not delay broadcasts to others.
+/// A failure only affects the peer in question, so it is logged instead of propagated.
+/// Returns whether the message was queued.
+fn broadcast_to<OpType: OpTrait + Serialize + DeserializeOwned>(
+    peer: &PeerConnection<OpType>,
+    msg: &Message<OpType>,
+) -> bool {
+    if let Err(err) = peer.try_send(msg) {
+        error!(
+            "Failed to send data to peer {}: {err}",
+            peer.get_identifier()
+        );
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    use futures::StreamExt;
+    use tokio_util::codec::FramedRead;
+
+    use super::LedgerWrapper;
+    use crate::encoding::{Encoding, WireCodec};
+    use crate::protocol::{EpochId, RejectReason};
+    use crate::server::capacity::EpochCapacity;
+    use crate::server::connection::PeerConnection;
+    use crate::server::epochs::EpochTrigger;
+    use crate::server::outbound::{OverflowPolicy, QueueConfig};
+    use crate::server::NullCallback;
+    use crate::{
+        generate_key_pair, to_account_id, AccountId, Error, LedgerMirror, StateError, StateMachine,
+        TestOperation, TimeBound, Transaction, Validity,
+    };
+
+    /// Counts the number of transactions issued by each account
+    #[derive(Default)]
+    struct TxCounter {
+        counts: BTreeMap<AccountId, usize>,
+    }
+
+    impl StateMachine<TestOperation> for TxCounter {
+        fn apply(&mut self, tx: &Transaction<TestOperation>) -> Result<(), StateError> {
+            *self.counts.entry(*tx.get_source()).or_default() += 1;
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            bincode::serialize(&self.counts).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+            self.counts = bincode::deserialize(snapshot)?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mirror_matches() {
+        let encoding = Encoding::Bincode;
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            100_000.0,
+            10,
+            5,
+            EpochCapacity::default(),
+            EpochTrigger::External,
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        let server_state = Arc::new(Mutex::new(TxCounter::default()));
+        wrapper.set_state_machine(server_state.clone()).await;
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        // Some transactions that the peer will only receive as part of the initial sync
+        for _ in 0..3 {
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            wrapper.insert(tx).await.unwrap();
+        }
+
+        let (server_side, client_side) = tokio::io::duplex(1024 * 1024);
+        let (conn, _read_framed) = PeerConnection::new(
+            1,
+            wrapper.clone(),
+            Arc::new(NullCallback {}),
+            server_side,
+            encoding,
+            QueueConfig::default(),
+        );
+        wrapper.register_peer(1, Arc::new(conn)).await.unwrap();
+
+        let num_epochs = 4;
+        let txs_per_epoch = 5;
+
+        for _ in 1..num_epochs {
+            for _ in 0..txs_per_epoch {
+                let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+                wrapper.insert(tx).await.unwrap();
+            }
+
+            wrapper.start_new_epoch().await.unwrap();
+        }
+
+        let client_state = Arc::new(Mutex::new(TxCounter::default()));
+        let mirror = LedgerMirror::<TestOperation>::with_state_machine(client_state.clone());
+        let mut framed = FramedRead::new(client_side, WireCodec::new(encoding));
+        let expected_txs = 3 + (num_epochs - 1) * txs_per_epoch;
+
+        while mirror.get_ledger().num_transactions() < expected_txs
+            || mirror.get_ledger().num_epochs() < num_epochs
+        {
+            let data = framed.next().await.unwrap().unwrap();
+            mirror
+                .handle_message(encoding.decode(&data).unwrap())
+                .unwrap();
+        }
+
+        for identifier in 0..num_epochs as EpochId {
+            let expected = bincode::serialize(&wrapper.get_epoch(identifier).unwrap()).unwrap();
+            let actual =
+                bincode::serialize(&mirror.get_ledger().get_epoch(identifier).unwrap()).unwrap();
+
+            assert_eq!(expected, actual);
+        }
 
-        spawn(async move {
-            sleep(latency).await;
+        assert_eq!(wrapper.state_digest(), mirror.state_digest());
+        assert_eq!(
+            client_state.lock().unwrap().counts.get(&account),
+            Some(&expected_txs)
+        );
+        assert_eq!(server_state.lock().unwrap().counts.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn epoch_overflow() {
+        let capacity = EpochCapacity {
+            max_transactions: Some(2),
+            ..Default::default()
+        };
+        let wrapper =
+            LedgerWrapper::<TestOperation>::new(100_000.0, 10, 0, capacity, EpochTrigger::External);
+        wrapper.start_new_epoch().await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let mut tasks = Vec::new();
+        for _ in 0..3 {
+            let wrapper = wrapper.clone();
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            tasks.push(tokio::spawn(async move { wrapper.insert(tx).await }));
+        }
+
+        // The third transaction is held back until the next epoch
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(wrapper.get_epoch(0).unwrap().size(), 2);
+        assert_eq!(tasks.iter().filter(|task| task.is_finished()).count(), 2);
+
+        wrapper.start_new_epoch().await.unwrap();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(wrapper.get_epoch(0).unwrap().size(), 2);
+        assert_eq!(wrapper.get_epoch(1).unwrap().size(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expiry() {
+        let capacity = EpochCapacity {
+            max_transactions: Some(1),
+            ..Default::default()
+        };
+        let wrapper =
+            LedgerWrapper::<TestOperation>::new(100_000.0, 10, 0, capacity, EpochTrigger::External);
+        wrapper.start_new_epoch().await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+        wrapper.insert(tx).await.unwrap();
+
+        let not_yet_valid = Validity {
+            not_before: Some(TimeBound::Epoch(5)),
+            not_after: None,
+        };
+        let tx = Transaction::new_with_validity(
+            account,
+            TestOperation::Empty {},
+            1,
+            not_yet_valid,
+            private_key.clone(),
+        );
+        assert!(matches!(
+            wrapper.insert(tx).await,
+            Err(Error::Rejected(RejectReason::NotYetValid))
+        ));
+
+        // Expires while waiting for the next epoch
+        let tx = Transaction::new_with_validity(
+            account,
+            TestOperation::Empty {},
+            1,
+            Validity::until_epoch(0),
+            private_key,
+        );
+        let task = {
+            let wrapper = wrapper.clone();
+            tokio::spawn(async move { wrapper.insert(tx).await })
+        };
 
-            trace!("Adding new transaction to the ledger");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        wrapper.start_new_epoch().await.unwrap();
 
-            let msg = Message::LedgerUpdate { transaction };
-            let mut futures = Vec::new();
+        assert!(matches!(
+            task.await.unwrap(),
+            Err(Error::Rejected(RejectReason::Expired))
+        ));
+        assert_eq!(wrapper.get_epoch(1).unwrap().size(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transaction_count_trigger() {
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            100_000.0,
+            10,
+            0,
+            EpochCapacity::default(),
+            EpochTrigger::TransactionCount(2),
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        for _ in 0..5 {
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            wrapper.insert(tx).await.unwrap();
+        }
+
+        assert_eq!(wrapper.num_epochs(), 3);
+
+        for (identifier, size) in [(0, 2), (1, 2), (2, 1)] {
+            let epoch = wrapper.get_epoch(identifier).unwrap();
+            assert_eq!(epoch.size(), size);
+            assert_eq!(epoch.is_sealed(), identifier < 2);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capacity_before_trigger() {
+        let capacity = EpochCapacity {
+            max_transactions: Some(2),
+            ..Default::default()
+        };
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            100_000.0,
+            10,
+            0,
+            capacity,
+            EpochTrigger::TransactionCount(3),
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        // The trigger can never be reached, so full epochs must be closed anyway
+        for _ in 0..5 {
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            tokio::time::timeout(std::time::Duration::from_secs(5), wrapper.insert(tx))
+                .await
+                .expect("Transaction was never admitted")
+                .unwrap();
+        }
+
+        assert_eq!(wrapper.num_epochs(), 3);
+        for (identifier, size) in [(0, 2), (1, 2), (2, 1)] {
+            assert_eq!(wrapper.get_epoch(identifier).unwrap().size(), size);
+        }
+    }
 
-            // broadcast
-            for peer in peers.values() {
-                futures.push(peer.send(&msg));
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch() {
+        let throughput = 1000.0;
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            throughput,
+            1,
+            0,
+            EpochCapacity::default(),
+            EpochTrigger::External,
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let num_transactions = 20;
+        let mut transactions: Vec<_> = (0..num_transactions)
+            .map(|_| Transaction::new(account, TestOperation::Empty {}, private_key.clone()))
+            .collect();
+
+        let not_yet_valid = Validity {
+            not_before: Some(TimeBound::Epoch(5)),
+            not_after: None,
+        };
+        transactions[3] = Transaction::new_with_validity(
+            account,
+            TestOperation::Empty {},
+            1,
+            not_yet_valid,
+            private_key,
+        );
+
+        let start = std::time::Instant::now();
+        let results = wrapper.insert_batch(transactions.clone()).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(results.len(), num_transactions);
+        for (pos, result) in results.into_iter().enumerate() {
+            if pos == 3 {
+                assert!(matches!(
+                    result,
+                    Err(Error::Rejected(RejectReason::NotYetValid))
+                ));
+            } else {
+                result.unwrap();
             }
+        }
+
+        // Every admitted transaction of the batch counts against the throughput limit
+        let min_duration =
+            std::time::Duration::from_secs_f64((num_transactions - 2) as f64 / throughput);
+        assert!(elapsed >= min_duration, "Batch took only {elapsed:?}");
 
-            for future in futures.drain(..) {
-                future.await;
+        // Transactions are committed in the order of the batch
+        let epoch = wrapper.get_epoch(0).unwrap();
+        let committed: Vec<_> = epoch
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect();
+        let expected: Vec<_> = transactions
+            .iter()
+            .enumerate()
+            .filter(|(pos, _)| *pos != 3)
+            .map(|(_, tx)| tx.get_id())
+            .collect();
+        assert_eq!(committed, expected);
+
+        let metrics = wrapper.metrics();
+        assert_eq!(
+            metrics
+                .transactions_submitted
+                .load(std::sync::atomic::Ordering::Relaxed),
+            num_transactions as u64
+        );
+        assert_eq!(
+            metrics
+                .transactions_accepted
+                .load(std::sync::atomic::Ordering::Relaxed),
+            num_transactions as u64 - 1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_epoch_keeps_identifier() {
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            100_000.0,
+            10,
+            0,
+            EpochCapacity::default(),
+            EpochTrigger::External,
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        // Occupy the next identifier so that creating the epoch fails
+        wrapper.ledger.create_new_epoch(1, 0).unwrap();
+
+        for _ in 0..2 {
+            assert!(matches!(
+                wrapper.start_new_epoch().await,
+                Err(Error::EpochAlreadyExists(1))
+            ));
+        }
+
+        assert!(!wrapper.get_epoch(0).unwrap().is_sealed());
+        assert_eq!(wrapper.next_epoch_id.load(Ordering::SeqCst), 1);
+        assert!(!wrapper.ledger.has_gaps());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_peer() {
+        let encoding = Encoding::Bincode;
+        let wrapper = LedgerWrapper::<TestOperation>::new(
+            100_000.0,
+            10,
+            0,
+            EpochCapacity::default(),
+            EpochTrigger::External,
+        );
+        wrapper.start_new_epoch().await.unwrap();
+
+        let queue = QueueConfig {
+            capacity: 2,
+            policy: OverflowPolicy::Block,
+        };
+
+        // Never reads anything, so its queue fills up quickly
+        let (slow_side, _slow_client) = tokio::io::duplex(64);
+        let (slow_conn, _) = PeerConnection::new(
+            1,
+            wrapper.clone(),
+            Arc::new(NullCallback {}),
+            slow_side,
+            encoding,
+            queue,
+        );
+        wrapper.register_peer(1, Arc::new(slow_conn)).await.unwrap();
+
+        let (server_side, client_side) = tokio::io::duplex(1024 * 1024);
+        let (conn, _) = PeerConnection::new(
+            2,
+            wrapper.clone(),
+            Arc::new(NullCallback {}),
+            server_side,
+            encoding,
+            queue,
+        );
+        wrapper.register_peer(2, Arc::new(conn)).await.unwrap();
+
+        let (private_key, public_key) = generate_key_pair();
+        let account = to_account_id(&public_key);
+
+        let num_transactions = 20;
+        for _ in 0..num_transactions {
+            let tx = Transaction::new(account, TestOperation::Empty {}, private_key.clone());
+            wrapper.insert(tx).await.unwrap();
+        }
+
+        // The slow peer is disconnected instead of holding up the others or growing its queue;
+        // the other peer still gets all updates, and new peers can still register
+        let mirror = LedgerMirror::<TestOperation>::default();
+        let mut framed = FramedRead::new(client_side, WireCodec::new(encoding));
+
+        let receive = async {
+            while mirror.get_ledger().num_transactions() < num_transactions {
+                let data = framed.next().await.unwrap().unwrap();
+                mirror
+                    .handle_message(encoding.decode(&data).unwrap())
+                    .unwrap();
             }
-        });
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), receive)
+            .await
+            .unwrap();
+
+        let (other_side, _other_client) = tokio::io::duplex(1024 * 1024);
+        let (other_conn, _) = PeerConnection::new(
+            3,
+            wrapper.clone(),
+            Arc::new(NullCallback {}),
+            other_side,
+            encoding,
+            queue,
+        );
+        let register = wrapper.register_peer(3, Arc::new(other_conn));
+        tokio::time::timeout(std::time::Duration::from_secs(10), register)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
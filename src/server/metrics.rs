@@ -0,0 +1,368 @@
+//! Counters and histograms describing what the server is doing
+//!
+//! They are rendered in the Prometheus text format, either by a small HTTP endpoint
+//! (`--metrics-address`) or, with the `gateway` feature, at `GET /metrics` of the gateway.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+use log::{debug, info};
+
+use crate::protocol::RejectReason;
+use crate::Error;
+
+pub const DEFAULT_METRICS_PORT: u16 = 8082;
+
+/// Upper bounds (in seconds) of the buckets for latencies
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0];
+
+/// Upper bounds of the buckets for the number of transactions in an epoch
+const EPOCH_SIZE_BUCKETS: &[f64] = &[0.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0];
+
+/// Upper bounds of the buckets for the serialized size of an epoch (in bytes)
+const EPOCH_BYTES_BUCKETS: &[f64] = &[1e3, 1e4, 1e5, 1e6, 1e7, 1e8];
+
+/// Reasons for which the ledger rejects transactions (invalid ones never reach it)
+const REJECT_REASONS: [RejectReason; 4] = [
+    RejectReason::Expired,
+    RejectReason::NotYetValid,
+    RejectReason::ExceedsEpochCapacity,
+    RejectReason::Unauthorized,
+];
+
+/// Distribution of observed values over fixed buckets
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Non-cumulative count for each bucket (plus one for values above all bounds)
+    buckets: Vec<AtomicU64>,
+    /// Bit pattern of an f64
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let pos = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[pos].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn get_count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+
+        cumulative += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {}", self.get_count());
+    }
+}
+
+/// All metrics of a server
+///
+/// Updated by `LedgerWrapper` (transactions and epochs) and `PeerConnection` (traffic).
+pub struct Metrics {
+    pub(super) transactions_submitted: AtomicU64,
+    pub(super) transactions_accepted: AtomicU64,
+    /// Transactions that failed validation (or were not issued by the authenticated account)
+    pub(super) transactions_discarded: AtomicU64,
+    transactions_rejected: [AtomicU64; REJECT_REASONS.len()],
+    /// Time a transaction was held back by the throughput limit
+    pub(super) admission_wait: Histogram,
+    /// Time from submission until a transaction was added to the ledger
+    pub(super) commit_latency: Histogram,
+    pub(super) epoch_transactions: Histogram,
+    pub(super) epoch_bytes: Histogram,
+    pub(super) connected_peers: AtomicI64,
+    bytes_sent: Mutex<BTreeMap<u32, Arc<AtomicU64>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            transactions_submitted: AtomicU64::new(0),
+            transactions_accepted: AtomicU64::new(0),
+            transactions_discarded: AtomicU64::new(0),
+            transactions_rejected: Default::default(),
+            admission_wait: Histogram::new(LATENCY_BUCKETS),
+            commit_latency: Histogram::new(LATENCY_BUCKETS),
+            epoch_transactions: Histogram::new(EPOCH_SIZE_BUCKETS),
+            epoch_bytes: Histogram::new(EPOCH_BYTES_BUCKETS),
+            connected_peers: AtomicI64::new(0),
+            bytes_sent: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub(super) fn record_rejection(&self, reason: RejectReason) {
+        let pos = REJECT_REASONS.iter().position(|r| *r == reason).unwrap();
+        self.transactions_rejected[pos].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counter for the bytes sent to the given peer
+    pub(super) fn bytes_sent_to(&self, peer: u32) -> Arc<AtomicU64> {
+        self.bytes_sent
+            .lock()
+            .unwrap()
+            .entry(peer)
+            .or_default()
+            .clone()
+    }
+
+    /// Stops reporting the traffic of a peer that disconnected
+    pub(super) fn remove_peer(&self, peer: u32) {
+        self.bytes_sent.lock().unwrap().remove(&peer);
+    }
+
+    pub fn get_transactions_accepted(&self) -> u64 {
+        self.transactions_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn get_connected_peers(&self) -> i64 {
+        self.connected_peers.load(Ordering::Relaxed)
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        render_counter(
+            &mut out,
+            "blocksim_transactions_submitted_total",
+            "Transactions submitted by clients",
+            self.transactions_submitted.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "blocksim_transactions_accepted_total",
+            "Transactions committed to the ledger",
+            self.get_transactions_accepted(),
+        );
+        render_counter(
+            &mut out,
+            "blocksim_transactions_discarded_total",
+            "Transactions that failed validation",
+            self.transactions_discarded.load(Ordering::Relaxed),
+        );
+
+        let name = "blocksim_transactions_rejected_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Transactions dropped instead of committed"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (reason, count) in REJECT_REASONS.iter().zip(&self.transactions_rejected) {
+            let _ = writeln!(
+                out,
+                "{name}{{reason=\"{}\"}} {}",
+                reason_label(*reason),
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        self.admission_wait.render(
+            &mut out,
+            "blocksim_admission_wait_seconds",
+            "Time transactions were held back by the throughput limit",
+        );
+        self.commit_latency.render(
+            &mut out,
+            "blocksim_commit_latency_seconds",
+            "Time from submission until a transaction was added to the ledger",
+        );
+        self.epoch_transactions.render(
+            &mut out,
+            "blocksim_epoch_transactions",
+            "Number of transactions in sealed epochs",
+        );
+        self.epoch_bytes.render(
+            &mut out,
+            "blocksim_epoch_bytes",
+            "Serialized size of sealed epochs",
+        );
+
+        let name = "blocksim_connected_peers";
+        let _ = writeln!(out, "# HELP {name} Peers currently connected");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", self.get_connected_peers());
+
+        let name = "blocksim_peer_bytes_sent_total";
+        let _ = writeln!(out, "# HELP {name} Bytes sent to each peer");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (peer, count) in self.bytes_sent.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{peer=\"{peer}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn reason_label(reason: RejectReason) -> &'static str {
+    match reason {
+        RejectReason::Expired => "expired",
+        RejectReason::NotYetValid => "not_yet_valid",
+        RejectReason::ExceedsEpochCapacity => "exceeds_epoch_capacity",
+        RejectReason::Unauthorized => "unauthorized",
+        RejectReason::Invalid => "invalid",
+    }
+}
+
+/// Upper bound for the request head of a metrics scrape
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Serves the metrics over plain HTTP until `stop` is cancelled
+///
+/// This only understands `GET /metrics`, which is all a scraper needs.
+pub(super) async fn run_metrics_server(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    stop: CancellationToken,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on {:?}", listener.local_addr()?);
+
+    loop {
+        let (socket, _) = tokio::select! {
+            result = listener.accept() => result?,
+            _ = stop.cancelled() => return Ok(()),
+        };
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_scrape(socket, &metrics).await {
+                debug!("Failed to serve metrics: {err}");
+            }
+        });
+    }
+}
+
+async fn serve_scrape(mut socket: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = socket.read(&mut buffer).await?;
+
+        if len == 0 || request.len() + len > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+
+        request.extend_from_slice(&buffer[..len]);
+    }
+
+    let is_metrics = request.starts_with(b"GET /metrics ") || request.starts_with(b"GET / ");
+
+    let (status, body) = if is_metrics {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::Metrics;
+    use crate::protocol::RejectReason;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+
+        metrics
+            .transactions_submitted
+            .fetch_add(3, Ordering::Relaxed);
+        metrics.record_rejection(RejectReason::Expired);
+        metrics
+            .transactions_discarded
+            .fetch_add(2, Ordering::Relaxed);
+        metrics.bytes_sent_to(7).fetch_add(100, Ordering::Relaxed);
+        metrics.bytes_sent_to(8).fetch_add(50, Ordering::Relaxed);
+        metrics.remove_peer(8);
+
+        metrics.epoch_transactions.observe(5.0);
+        metrics.epoch_transactions.observe(50.0);
+        metrics.epoch_transactions.observe(1e9);
+
+        let text = metrics.render();
+
+        assert!(text.contains("blocksim_transactions_submitted_total 3\n"));
+        assert!(text.contains("blocksim_transactions_rejected_total{reason=\"expired\"} 1\n"));
+        assert!(text.contains("blocksim_transactions_rejected_total{reason=\"unauthorized\"} 0\n"));
+        assert!(text.contains("blocksim_transactions_discarded_total 2\n"));
+        assert!(text.contains("blocksim_peer_bytes_sent_total{peer=\"7\"} 100\n"));
+        assert!(!text.contains("peer=\"8\""));
+
+        assert!(text.contains("blocksim_epoch_transactions_bucket{le=\"10\"} 1\n"));
+        assert!(text.contains("blocksim_epoch_transactions_bucket{le=\"100\"} 2\n"));
+        assert!(text.contains("blocksim_epoch_transactions_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("blocksim_epoch_transactions_sum 1000000055\n"));
+        assert!(text.contains("blocksim_epoch_transactions_count 3\n"));
+    }
+}
@@ -1,16 +1,56 @@
+mod auth;
+pub use auth::AuthPolicy;
+
 mod connection;
-use connection::PeerConnection;
 pub use connection::{Callback, NullCallback};
+use connection::{PeerConnection, PeerStream};
+
+mod admission;
+
+mod capacity;
+pub use capacity::{EpochCapacity, EpochUsage};
+
+mod epochs;
+pub use epochs::EpochTrigger;
 
 mod ledger_wrapper;
 use ledger_wrapper::LedgerWrapper;
 
+mod outbound;
+pub use outbound::{OverflowPolicy, QueueConfig, QueueStats};
+
+mod config;
+pub use config::{ConfigFile, ServerConfig, ServerConfigBuilder};
+
+mod metrics;
+pub use metrics::{Metrics, DEFAULT_METRICS_PORT};
+
+pub mod storage;
+use storage::EpochStorage;
+
+pub mod trace;
+use trace::{TraceFormat, TraceWriter};
+
+#[cfg(feature = "gateway")]
+mod gateway;
+#[cfg(feature = "gateway")]
+pub use gateway::DEFAULT_GATEWAY_PORT;
+
+#[cfg(feature = "tls")]
+mod tls;
+
 use clap::Parser;
 
+use tokio::io::DuplexStream;
 use tokio::net::TcpListener;
 use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
 
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -19,92 +59,549 @@ use serde::Serialize;
 
 use log::{error, info};
 
-use crate::{OpTrait, DEFAULT_BLOCKCHAIN_PORT};
-
-fn parse_address(addr_str: &str, default_port: u16) -> SocketAddr {
-    if addr_str.contains(':') {
-        addr_str.to_socket_addrs().unwrap().next().unwrap()
-    } else {
-        let addr_str = format!("{addr_str}:{default_port}");
-        addr_str.to_socket_addrs().unwrap().next().unwrap()
-    }
-}
+use crate::encoding::{read_handshake, write_handshake, Encoding, WireCodec};
+use crate::{AccountId, Error, OpTrait, SharedStateMachine};
 
 #[derive(Parser)]
 #[clap(about = "Simulates a blockchain network using a single process")]
 struct Args {
+    #[clap(
+        long,
+        short = 'c',
+        help = "TOML file to load the configuration from (command line flags take precedence)"
+    )]
+    config: Option<PathBuf>,
     #[clap(
         long,
         short = 'l',
-        help = "The address to listen for client connections",
-        default_value = "0.0.0.0"
+        help = "The address to listen for client connections [default: 0.0.0.0]"
+    )]
+    listen_address: Option<String>,
+    #[clap(
+        long,
+        help = "The maximum throughput of the chain (in tx/s) [default: 1000]"
+    )]
+    throughput: Option<f64>,
+    #[clap(
+        long,
+        help = "How many transactions can be admitted at once without exceeding the throughput [default: 1]"
+    )]
+    burst: Option<u32>,
+    #[clap(
+        long,
+        help = "The transaction confirmation delay (in ms) [default: 100]"
+    )]
+    latency: Option<u32>,
+    #[clap(long, help = "Length of an epoch (in s) [default: 60]")]
+    epoch_length: Option<u64>,
+    #[clap(
+        long,
+        help = "When to start a new epoch: interval:<s>, transactions:<n>, bytes:<n>, poisson:<mean s>, or external (overrides --epoch-length)"
+    )]
+    epoch_trigger: Option<EpochTrigger>,
+    #[clap(
+        long,
+        help = "The maximum number of transactions per epoch (excess transactions wait for the next epoch)"
+    )]
+    max_epoch_transactions: Option<usize>,
+    #[clap(
+        long,
+        help = "The maximum serialized size of all transactions in an epoch (in bytes)"
+    )]
+    max_epoch_bytes: Option<u64>,
+    #[clap(
+        long,
+        help = "The maximum total declared cost of all transactions in an epoch"
+    )]
+    max_epoch_cost: Option<u64>,
+    #[cfg(feature = "gateway")]
+    #[clap(
+        long,
+        help = "The address to serve the HTTP/WebSocket gateway on (disabled if not set)"
+    )]
+    http_address: Option<String>,
+    #[clap(
+        long,
+        help = "The maximum number of messages queued for a single peer [default: 10000]"
+    )]
+    queue_size: Option<usize>,
+    #[clap(
+        long,
+        help = "What to do when the queue of a peer is full [default: drop-peer]",
+        value_enum
+    )]
+    queue_policy: Option<OverflowPolicy>,
+    #[clap(
+        long,
+        help = "Require clients to prove they own an account before connecting"
+    )]
+    require_auth: bool,
+    #[clap(
+        long,
+        help = "Only allow this account to connect (can be given multiple times; implies --require-auth)"
+    )]
+    allowed_account: Vec<AccountId>,
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        help = "PEM file containing the TLS certificate chain (enables TLS)",
+        requires = "tls_key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        help = "PEM file containing the TLS private key",
+        requires = "tls_cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "File to write all sealed epochs to (overwritten if it exists)"
+    )]
+    storage: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "The address to serve Prometheus metrics on (disabled if not set)"
     )]
-    listen_address: String,
+    metrics_address: Option<String>,
     #[clap(
         long,
-        help = "The maximum throughput of the chain (in tx/s)",
-        default_value_t = 1000.0
+        help = "File to record a trace of all server events to (overwritten if it exists)"
     )]
-    throughput: f64,
+    trace: Option<PathBuf>,
     #[clap(
         long,
-        help = "The transaction confirmation delay (in ms)",
-        default_value_t = 100
+        help = "Format of the trace [default: csv for *.csv files, json otherwise]",
+        value_enum,
+        requires = "trace"
     )]
-    latency: u32,
-    #[clap(long, help = "Length of an epoch (in s)", default_value_t = 60)]
-    epoch_length: u64,
+    trace_format: Option<TraceFormat>,
+}
+
+impl Args {
+    /// The configuration entries that were set on the command line
+    fn into_overrides(self) -> ConfigFile {
+        let epoch_trigger = self.epoch_trigger.or(self
+            .epoch_length
+            .map(|secs| EpochTrigger::Interval(Duration::from_secs(secs))));
+
+        ConfigFile {
+            listen_address: self.listen_address,
+            throughput: self.throughput,
+            burst: self.burst,
+            latency: self.latency,
+            // Folded into the trigger, so that it replaces a trigger set in the file
+            epoch_length: None,
+            epoch_trigger,
+            max_epoch_transactions: self.max_epoch_transactions,
+            max_epoch_bytes: self.max_epoch_bytes,
+            max_epoch_cost: self.max_epoch_cost,
+            queue_size: self.queue_size,
+            queue_policy: self.queue_policy,
+            require_auth: self.require_auth.then_some(true),
+            allowed_accounts: (!self.allowed_account.is_empty()).then_some(self.allowed_account),
+            #[cfg(feature = "gateway")]
+            http_address: self.http_address,
+            #[cfg(feature = "tls")]
+            tls_cert: self.tls_cert,
+            #[cfg(feature = "tls")]
+            tls_key: self.tls_key,
+            storage: self.storage,
+            metrics_address: self.metrics_address,
+            trace: self.trace,
+            trace_format: self.trace_format,
+        }
+    }
+}
+
+/// Everything needed to set up the connection to a new peer
+struct PeerSetup<OpType: OpTrait> {
+    ledger: Arc<LedgerWrapper<OpType>>,
+    callback: Arc<dyn Callback<OpType>>,
+    auth_policy: AuthPolicy,
+    queue_config: QueueConfig,
+    next_id: AtomicU32,
+}
+
+impl<OpType: OpTrait> PeerSetup<OpType> {
+    fn next_identifier(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// Performs connection setup and then handles messages until the peer disconnects
+async fn handle_peer<OpType: OpTrait + Serialize + DeserializeOwned>(
+    identifier: u32,
+    mut socket: impl PeerStream + Unpin,
+    setup: Arc<PeerSetup<OpType>>,
+) {
+    let encoding = match read_handshake(&mut socket).await {
+        Ok(encoding) => encoding,
+        Err(err) => {
+            error!("Handshake with peer {identifier} failed: {err}");
+            return;
+        }
+    };
+
+    info!("Peer {identifier} uses the {encoding} encoding");
+
+    let (mut conn, mut read_socket) = PeerConnection::new(
+        identifier,
+        setup.ledger.clone(),
+        setup.callback.clone(),
+        socket,
+        encoding,
+        setup.queue_config,
+    );
+
+    match conn
+        .authenticate(&mut read_socket, &setup.auth_policy)
+        .await
+    {
+        Ok(Some(account)) => info!("Peer {identifier} authenticated as account {account}"),
+        Ok(None) => {}
+        Err(err) => {
+            error!("Rejected peer {identifier}: {err}");
+            return;
+        }
+    }
+
+    let conn = Arc::new(conn);
+    if let Err(err) = setup.ledger.register_peer(identifier, conn.clone()).await {
+        error!("Failed to register peer {identifier}: {err}");
+        conn.disconnect().await;
+        return;
+    }
+
+    conn.run(read_socket).await;
 }
 
 pub async fn main_thread<OpType: OpTrait + Serialize + DeserializeOwned>(
     callback: Arc<dyn Callback<OpType>>,
 ) {
-    let args = Args::parse();
-    if args.throughput <= 0.0 {
-        panic!("Throughput cannot be <=0");
+    main_thread_with_state_machine(callback, None).await
+}
+
+/// Like `main_thread` but additionally drives the given state machine from all committed transactions
+pub async fn main_thread_with_state_machine<OpType: OpTrait + Serialize + DeserializeOwned>(
+    callback: Arc<dyn Callback<OpType>>,
+    state_machine: Option<SharedStateMachine<OpType>>,
+) {
+    let mut args = Args::parse();
+
+    let file = match args.config.take() {
+        Some(path) => ConfigFile::load(&path)
+            .unwrap_or_else(|err| panic!("Failed to load {}: {err}", path.display())),
+        None => ConfigFile::default(),
+    };
+
+    let mut builder = file
+        .merge(args.into_overrides())
+        .apply(ServerConfig::builder(callback))
+        .unwrap_or_else(|err| panic!("{err}"));
+
+    if let Some(state_machine) = state_machine {
+        builder = builder.state_machine(state_machine);
+    }
+
+    let config = builder.build().unwrap_or_else(|err| panic!("{err}"));
+
+    if let Err(err) = run_server(config).await {
+        panic!("Server failed: {err}");
     }
+}
+
+/// Runs a server with the given configuration until the process receives SIGINT or SIGTERM
+///
+/// The server then shuts down gracefully (see `ServerHandle::shutdown`).
+pub async fn run_server<OpType: OpTrait + Serialize + DeserializeOwned>(
+    config: ServerConfig<OpType>,
+) -> Result<(), Error> {
+    let handle = start_server(config).await?;
+
+    wait_for_signal().await?;
+    info!("Shutting down");
+
+    handle.shutdown().await
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> Result<(), Error> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Buffer size (per direction) of connections created by `ServerHandle::connect`
+const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
 
+/// A running server, created by `start_server`
+///
+/// Dropping the handle does not stop the server.
+pub struct ServerHandle<OpType: OpTrait> {
+    local_address: Option<SocketAddr>,
+    peers: Arc<PeerSetup<OpType>>,
+    stop: CancellationToken,
+    listener: Option<JoinHandle<()>>,
+    metrics_server: Option<JoinHandle<()>>,
+    /// The gateway is stopped after the ledger, so it can forward the final events
+    #[cfg(feature = "gateway")]
+    gateway: Option<(CancellationToken, JoinHandle<()>)>,
+}
+
+impl<OpType: OpTrait + Serialize + DeserializeOwned> ServerHandle<OpType> {
+    /// The address the server accepts TCP connections on (None for in-memory servers)
+    ///
+    /// Useful if the server was configured to listen on port 0.
+    pub fn local_address(&self) -> Option<SocketAddr> {
+        self.local_address
+    }
+
+    /// Connects a client through an in-memory stream instead of TCP (or TLS)
+    ///
+    /// The handshake has already been sent, so the client can exchange messages
+    /// in the given encoding right away. Authentication works as for other clients.
+    pub async fn connect(
+        &self,
+        encoding: Encoding,
+    ) -> Result<Framed<DuplexStream, WireCodec>, Error> {
+        let (mut client_side, server_side) = tokio::io::duplex(IN_MEMORY_BUFFER_SIZE);
+
+        let identifier = self.peers.next_identifier();
+        info!("Got new in-memory connection (peer {identifier})");
+
+        spawn(handle_peer(identifier, server_side, self.peers.clone()));
+
+        write_handshake(&mut client_side, encoding).await?;
+        Ok(Framed::new(client_side, WireCodec::new(encoding)))
+    }
+
+    /// Counters and histograms of this server (also served with `--metrics-address`)
+    pub fn metrics(&self) -> &Metrics {
+        self.peers.ledger.metrics()
+    }
+
+    /// Resolves once at least `count` transactions have been committed to the ledger
+    ///
+    /// Peers receive committed transactions only after the configured latency.
+    pub async fn wait_for_transactions(&self, count: usize) {
+        let mut committed = self.peers.ledger.subscribe_committed();
+
+        // The ledger (and thus the sender) lives as long as this handle
+        let _ = committed.wait_for(|num| *num >= count).await;
+    }
+
+    /// Stops the server
+    ///
+    /// No more connections or transactions are accepted. The current epoch is sealed and,
+    /// if storage is configured, written to disk. Connected peers receive all pending
+    /// messages before their connection is closed.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.stop.cancel();
+
+        if let Some(listener) = self.listener {
+            if let Err(err) = listener.await {
+                error!("Listener task failed: {err}");
+            }
+        }
+
+        if let Some(metrics_server) = self.metrics_server {
+            if let Err(err) = metrics_server.await {
+                error!("Metrics task failed: {err}");
+            }
+        }
+
+        self.peers.ledger.shutdown().await?;
+        #[cfg(feature = "gateway")]
+        if let Some((stop, gateway)) = self.gateway {
+            stop.cancel();
+
+            if let Err(err) = gateway.await {
+                error!("HTTP gateway task failed: {err}");
+            }
+        }
+
+        info!("Server shut down");
+        Ok(())
+    }
+}
+
+/// Starts a server with the given configuration and returns once it accepts connections
+pub async fn start_server<OpType: OpTrait + Serialize + DeserializeOwned>(
+    config: ServerConfig<OpType>,
+) -> Result<ServerHandle<OpType>, Error> {
     info!(
-        "Ledger throughput set to {}tx/s and latency set to {}ms",
-        args.throughput, args.latency
+        "Ledger throughput set to {}tx/s (burst of {}) and latency set to {}ms",
+        config.throughput, config.burst, config.latency_ms
     );
 
-    let addr = parse_address(&args.listen_address, DEFAULT_BLOCKCHAIN_PORT);
-    info!("Listening for connections on {addr:?}");
+    if !config.capacity.is_unbounded() {
+        info!("Epoch capacity set to {:?}", config.capacity);
+    }
 
-    let ledger = Arc::new(LedgerWrapper::new(args.throughput, args.latency));
-    let listener = TcpListener::bind(&addr)
-        .await
-        .expect("Failed to bind socket!");
+    info!("New epochs are triggered by {}", config.epoch_trigger);
 
-    let l2 = ledger.clone();
+    let ledger = LedgerWrapper::new(
+        config.throughput,
+        config.burst,
+        config.latency_ms,
+        config.capacity,
+        config.epoch_trigger,
+    );
 
-    let epoch_length = Duration::from_secs(args.epoch_length);
+    if let Some(state_machine) = config.state_machine {
+        ledger.set_state_machine(state_machine).await;
+    }
 
-    tokio::spawn(async move {
-        loop {
-            l2.start_new_epoch().await;
-            tokio::time::sleep(epoch_length).await;
+    if let Some(path) = &config.storage {
+        info!("Writing sealed epochs to {}", path.display());
+        ledger.set_storage(EpochStorage::create(path)?);
+    }
+
+    if let Some((path, format)) = &config.trace {
+        info!("Recording a trace to {}", path.display());
+        ledger.trace().start(TraceWriter::create(path, *format)?);
+    }
+
+    let callback = config.callback;
+    let stop = CancellationToken::new();
+
+    #[cfg(feature = "gateway")]
+    let gateway = config.http_address.map(|addr| {
+        let ledger = ledger.clone();
+        let callback = callback.clone();
+        let stop = CancellationToken::new();
+
+        let task = spawn({
+            let stop = stop.clone();
+
+            async move {
+                if let Err(err) = gateway::run_gateway(addr, ledger, callback, stop).await {
+                    error!("HTTP gateway failed: {err}");
+                }
+            }
+        });
+
+        (stop, task)
+    });
+
+    let metrics_server = config.metrics_address.map(|addr| {
+        let metrics = ledger.metrics().clone();
+        let stop = stop.clone();
+
+        spawn(async move {
+            if let Err(err) = metrics::run_metrics_server(addr, metrics, stop).await {
+                error!("Metrics server failed: {err}");
+            }
+        })
+    });
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match &config.tls {
+        Some((cert, key)) => {
+            info!("Using TLS for client connections");
+            Some(tls::load_acceptor(cert, key)?)
         }
+        None => None,
+    };
+
+    let peers = Arc::new(PeerSetup {
+        ledger: ledger.clone(),
+        callback,
+        auth_policy: config.auth_policy,
+        queue_config: config.queue,
+        next_id: AtomicU32::new(1),
     });
 
-    let mut next_id: u32 = 1;
+    // Time-based triggers keep starting new epochs from here on
+    ledger.start_new_epoch().await?;
+
+    let (local_address, listener) = match config.listen_address {
+        Some(addr) => {
+            let listener = TcpListener::bind(&addr).await?;
+            let local_address = listener.local_addr()?;
+            info!("Listening for connections on {local_address:?}");
 
+            let task = spawn(accept_peers(
+                listener,
+                peers.clone(),
+                stop.clone(),
+                #[cfg(feature = "tls")]
+                tls_acceptor,
+            ));
+
+            (Some(local_address), Some(task))
+        }
+        None => {
+            info!("Not listening for TCP connections");
+            (None, None)
+        }
+    };
+
+    Ok(ServerHandle {
+        local_address,
+        peers,
+        stop,
+        listener,
+        metrics_server,
+        #[cfg(feature = "gateway")]
+        gateway,
+    })
+}
+
+/// Accepts TCP connections until `stop` is cancelled
+async fn accept_peers<OpType: OpTrait + Serialize + DeserializeOwned>(
+    listener: TcpListener,
+    peers: Arc<PeerSetup<OpType>>,
+    stop: CancellationToken,
+    #[cfg(feature = "tls")] tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+) {
     loop {
-        match listener.accept().await {
+        let result = tokio::select! {
+            result = listener.accept() => result,
+            _ = stop.cancelled() => break,
+        };
+
+        match result {
             Ok((socket, addr)) => {
                 info!("Got new connection from {addr}");
-                let id = next_id;
-                next_id += 1;
-
-                let (c, read_socket) =
-                    PeerConnection::new(id, ledger.clone(), callback.clone(), socket);
+                let id = peers.next_identifier();
+                let peers = peers.clone();
 
-                let conn = Arc::new(c);
-                ledger.register_peer(id, conn.clone()).await;
+                #[cfg(feature = "tls")]
+                let tls_acceptor = tls_acceptor.clone();
 
+                // Do the handshake in a separate task so a slow client cannot block the listener
                 spawn(async move {
-                    conn.run(read_socket).await;
+                    #[cfg(feature = "tls")]
+                    if let Some(acceptor) = tls_acceptor {
+                        match acceptor.accept(socket).await {
+                            Ok(stream) => {
+                                handle_peer(id, stream, peers).await;
+                            }
+                            Err(err) => {
+                                error!("TLS handshake with peer {id} failed: {err}")
+                            }
+                        }
+
+                        return;
+                    }
+
+                    handle_peer(id, socket, peers).await;
                 });
             }
             Err(err) => {
@@ -112,4 +609,241 @@ pub async fn main_thread<OpType: OpTrait + Serialize + DeserializeOwned>(
             }
         }
     }
+
+    info!("Stopped accepting connections");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    use super::trace::{read_trace, TraceEvent, TraceFormat};
+    use super::{start_server, storage, Callback, EpochTrigger, NullCallback, ServerConfig};
+    use crate::encoding::{write_handshake, Encoding, WireCodec};
+    use crate::protocol::{Message, RejectReason};
+    use crate::{generate_key_pair, to_account_id, LedgerMirror, TestOperation, Transaction};
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let path = std::env::temp_dir().join(format!("shutdown-{}.bin", std::process::id()));
+        let encoding = Encoding::Bincode;
+
+        let config = ServerConfig::<TestOperation>::builder(Arc::new(NullCallback {}))
+            .listen_address("127.0.0.1:0".parse().unwrap())
+            .latency_ms(0)
+            .epoch_trigger(EpochTrigger::External)
+            .storage(path.clone())
+            .build()
+            .unwrap();
+        let server = start_server(config).await.unwrap();
+
+        let mut stream = TcpStream::connect(server.local_address().unwrap())
+            .await
+            .unwrap();
+        write_handshake(&mut stream, encoding).await.unwrap();
+        let mut framed = Framed::new(stream, WireCodec::new(encoding));
+
+        let data = framed.next().await.unwrap().unwrap();
+        let msg: Message<TestOperation> = encoding.decode(&data).unwrap();
+        assert!(matches!(msg, Message::SyncEpoch { identifier: 0, .. }));
+
+        let (skey, pkey) = generate_key_pair();
+        let transaction = Transaction::new(to_account_id(&pkey), TestOperation::Empty {}, skey);
+        let data = encoding
+            .encode(&Message::TransactionRequest { transaction })
+            .unwrap();
+        framed.send(data.into()).await.unwrap();
+
+        let data = framed.next().await.unwrap().unwrap();
+        let msg: Message<TestOperation> = encoding.decode(&data).unwrap();
+        assert!(matches!(msg, Message::LedgerUpdate { .. }));
+
+        let shutdown = tokio::spawn(server.shutdown());
+
+        // The final epoch is sealed before the connection is closed
+        let data = framed.next().await.unwrap().unwrap();
+        let msg: Message<TestOperation> = encoding.decode(&data).unwrap();
+        let Message::EpochSealed {
+            identifier,
+            statistics,
+            ..
+        } = msg
+        else {
+            panic!("Expected EpochSealed but got {msg:?}");
+        };
+        assert_eq!(identifier, 0);
+        assert_eq!(statistics.num_transactions, 1);
+
+        assert!(framed.next().await.is_none());
+        shutdown.await.unwrap().unwrap();
+
+        let epochs = storage::read_epochs::<TestOperation>(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(epochs.len(), 1);
+        assert_eq!(epochs[0].1.size(), 1);
+    }
+
+    /// Does not accept any transaction
+    struct RejectAll;
+
+    impl Callback<TestOperation> for RejectAll {
+        fn validate_transaction(&self, _: &Transaction<TestOperation>) -> bool {
+            false
+        }
+
+        fn notify_new_transaction(&self, _: &Transaction<TestOperation>) {}
+    }
+
+    #[tokio::test]
+    async fn invalid_transaction() {
+        let encoding = Encoding::Bincode;
+
+        let config = ServerConfig::<TestOperation>::builder(Arc::new(RejectAll))
+            .in_memory()
+            .latency_ms(0)
+            .build()
+            .unwrap();
+        let server = start_server(config).await.unwrap();
+        let mut client = server.connect(encoding).await.unwrap();
+
+        let (skey, pkey) = generate_key_pair();
+        let transaction = Transaction::new(to_account_id(&pkey), TestOperation::Empty {}, skey);
+        let expected_id = transaction.get_id();
+        let data = encoding
+            .encode(&Message::TransactionRequest { transaction })
+            .unwrap();
+        client.send(data.into()).await.unwrap();
+
+        loop {
+            let data = client.next().await.unwrap().unwrap();
+            match encoding.decode::<Message<TestOperation>>(&data).unwrap() {
+                Message::TransactionRejected { id, reason } => {
+                    assert_eq!(id, expected_id);
+                    assert_eq!(reason, RejectReason::Invalid);
+                    break;
+                }
+                Message::LedgerUpdate { .. } => panic!("Invalid transaction was committed"),
+                _ => {}
+            }
+        }
+
+        assert!(server
+            .metrics()
+            .render()
+            .contains("blocksim_transactions_discarded_total 1\n"));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        let num_transactions = 20;
+        let encoding = Encoding::Json;
+        let trace_path = std::env::temp_dir().join(format!("in-memory-{}.csv", std::process::id()));
+
+        let config = ServerConfig::<TestOperation>::builder(Arc::new(NullCallback {}))
+            .in_memory()
+            .throughput(10_000.0)
+            .latency_ms(10)
+            .trace(trace_path.clone(), TraceFormat::Csv)
+            .build()
+            .unwrap();
+        let server = start_server(config).await.unwrap();
+        assert!(server.local_address().is_none());
+
+        let mirror = Arc::new(LedgerMirror::<TestOperation>::default());
+        let mut observer = server.connect(encoding).await.unwrap();
+
+        let receiver = {
+            let mirror = mirror.clone();
+
+            tokio::spawn(async move {
+                while let Some(data) = observer.next().await {
+                    let msg = encoding.decode(&data.unwrap()).unwrap();
+                    mirror.handle_message(msg).unwrap();
+                }
+            })
+        };
+
+        let mut client = server.connect(encoding).await.unwrap();
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        for _ in 0..num_transactions {
+            let transaction = Transaction::new(account, TestOperation::Empty {}, skey.clone());
+            let data = encoding
+                .encode(&Message::TransactionRequest { transaction })
+                .unwrap();
+            client.send(data.into()).await.unwrap();
+        }
+
+        server.wait_for_transactions(num_transactions).await;
+
+        let waiting = mirror.clone();
+        let complete = tokio::task::spawn_blocking(move || {
+            waiting.wait_for_transactions(num_transactions, Duration::from_secs(10))
+        })
+        .await
+        .unwrap();
+        assert!(complete);
+
+        let metrics = server.metrics();
+        assert_eq!(metrics.get_transactions_accepted(), num_transactions as u64);
+        assert_eq!(metrics.get_connected_peers(), 2);
+        assert!(metrics.render().contains(&format!(
+            "blocksim_transactions_submitted_total {num_transactions}\n"
+        )));
+
+        // The observer is disconnected once it received the sealed epoch
+        server.shutdown().await.unwrap();
+        receiver.await.unwrap();
+
+        let ledger = mirror.get_ledger();
+        assert_eq!(ledger.num_transactions(), num_transactions);
+        assert!(ledger.get_epoch(0).unwrap().is_sealed());
+
+        let trace = read_trace(&trace_path, TraceFormat::Csv).unwrap();
+        std::fs::remove_file(&trace_path).unwrap();
+
+        let count = |f: fn(&TraceEvent) -> bool| trace.iter().filter(|e| f(&e.event)).count();
+        assert_eq!(
+            count(|e| matches!(e, TraceEvent::TransactionReceived { .. })),
+            num_transactions
+        );
+        assert_eq!(
+            count(|e| matches!(e, TraceEvent::TransactionCommitted { .. })),
+            num_transactions
+        );
+        // Both clients connected after the first epoch started and receive every update and the sealed epoch
+        assert_eq!(
+            count(|e| matches!(e, TraceEvent::BroadcastQueued { .. })),
+            2 * (num_transactions + 1)
+        );
+        assert!(matches!(
+            trace.first().unwrap().event,
+            TraceEvent::EpochStarted { epoch: 0, .. }
+        ));
+
+        // Received transactions are recorded in full
+        for entry in &trace {
+            if let TraceEvent::TransactionReceived {
+                transaction, data, ..
+            } = &entry.event
+            {
+                let recorded: Transaction<TestOperation> = bincode::deserialize(data).unwrap();
+                assert_eq!(recorded.get_id(), *transaction);
+                assert_eq!(*recorded.get_source(), account);
+            }
+        }
+        assert!(matches!(
+            trace.last().unwrap().event,
+            TraceEvent::BroadcastQueued { .. }
+        ));
+    }
 }
@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::Error;
+
+/// What to do when a peer does not read messages fast enough
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Disconnect the peer
+    #[default]
+    DropPeer,
+    /// Discard the oldest queued message
+    DropOldest,
+    /// Wait until there is space in the queue
+    ///
+    /// Only replies to the peer's own requests wait. Broadcasts must not hold up other peers,
+    /// so a broadcast that does not fit disconnects the peer (as with `DropPeer`).
+    Block,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Number of messages currently waiting to be sent
+    pub depth: usize,
+    /// Highest number of messages that were waiting at any point
+    pub max_depth: usize,
+    /// Number of messages discarded because the queue was full
+    pub dropped: u64,
+}
+
+/// Bounded queue of (already encoded) messages for a single peer
+pub struct OutboundQueue {
+    config: QueueConfig,
+    messages: Mutex<VecDeque<Bytes>>,
+    data_available: Notify,
+    space_available: Notify,
+    closed: CancellationToken,
+    /// No new messages are accepted, but queued ones are still sent
+    finishing: AtomicBool,
+    max_depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl OutboundQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        assert!(config.capacity > 0, "Queue capacity must be positive");
+
+        Self {
+            config,
+            messages: Mutex::new(VecDeque::new()),
+            data_available: Notify::new(),
+            space_available: Notify::new(),
+            closed: CancellationToken::new(),
+            finishing: AtomicBool::new(false),
+            max_depth: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn push(&self, data: Bytes) -> Result<(), Error> {
+        loop {
+            // Create this before checking the queue so we cannot miss a wakeup
+            let space_available = self.space_available.notified();
+
+            {
+                let mut messages = self.messages.lock().unwrap();
+
+                if self.closed.is_cancelled() || self.finishing.load(Ordering::SeqCst) {
+                    return Err(Error::PeerDisconnected);
+                }
+
+                if messages.len() >= self.config.capacity {
+                    match self.config.policy {
+                        OverflowPolicy::DropPeer => {
+                            drop(messages);
+                            self.close();
+                            return Err(Error::QueueFull);
+                        }
+                        OverflowPolicy::DropOldest => {
+                            messages.pop_front();
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        OverflowPolicy::Block => {}
+                    }
+                }
+
+                if messages.len() < self.config.capacity {
+                    self.append(&mut messages, data);
+                    return Ok(());
+                }
+            }
+
+            tokio::select! {
+                _ = space_available => {}
+                _ = self.closed.cancelled() => return Err(Error::PeerDisconnected),
+            }
+        }
+    }
+
+    /// Queues a message without waiting for space in the queue
+    ///
+    /// Used for messages that are sent to many peers at once, so that a slow peer cannot
+    /// hold up the others. As this cannot wait, `OverflowPolicy::Block` behaves like `DropPeer`.
+    pub fn try_push(&self, data: Bytes) -> Result<(), Error> {
+        let mut messages = self.messages.lock().unwrap();
+
+        if self.closed.is_cancelled() || self.finishing.load(Ordering::SeqCst) {
+            return Err(Error::PeerDisconnected);
+        }
+
+        if messages.len() >= self.config.capacity {
+            match self.config.policy {
+                OverflowPolicy::DropPeer | OverflowPolicy::Block => {
+                    drop(messages);
+                    self.close();
+                    return Err(Error::QueueFull);
+                }
+                OverflowPolicy::DropOldest => {
+                    messages.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        self.append(&mut messages, data);
+        Ok(())
+    }
+
+    /// Queues a message regardless of the capacity (e.g., for the initial sync of a peer)
+    pub fn push_unbounded(&self, data: Bytes) -> Result<(), Error> {
+        let mut messages = self.messages.lock().unwrap();
+
+        if self.closed.is_cancelled() || self.finishing.load(Ordering::SeqCst) {
+            return Err(Error::PeerDisconnected);
+        }
+
+        self.append(&mut messages, data);
+        Ok(())
+    }
+
+    fn append(&self, messages: &mut VecDeque<Bytes>, data: Bytes) {
+        messages.push_back(data);
+        self.max_depth.fetch_max(messages.len(), Ordering::Relaxed);
+        self.data_available.notify_one();
+    }
+
+    /// Waits for messages and removes all of them from the queue
+    ///
+    /// Returns None once the queue has been closed, or once it is empty after `finish` was called.
+    pub async fn pop_all(&self) -> Option<Vec<Bytes>> {
+        loop {
+            {
+                let mut messages = self.messages.lock().unwrap();
+
+                if self.closed.is_cancelled() {
+                    return None;
+                }
+
+                if !messages.is_empty() {
+                    let batch = messages.drain(..).collect();
+                    self.space_available.notify_waiters();
+                    return Some(batch);
+                }
+
+                if self.finishing.load(Ordering::SeqCst) {
+                    return None;
+                }
+            }
+
+            tokio::select! {
+                _ = self.data_available.notified() => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Rejects new messages, but lets the writer send the ones that are already queued
+    pub fn finish(&self) {
+        // Hold the lock so a concurrent push or pop_all sees a consistent state
+        let _messages = self.messages.lock().unwrap();
+
+        self.finishing.store(true, Ordering::SeqCst);
+        self.data_available.notify_one();
+        self.space_available.notify_waiters();
+    }
+
+    /// Resolves once the queue has been closed
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.messages.lock().unwrap().len(),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::{OutboundQueue, OverflowPolicy, QueueConfig};
+    use crate::Error;
+
+    fn make_queue(policy: OverflowPolicy) -> Arc<OutboundQueue> {
+        Arc::new(OutboundQueue::new(QueueConfig {
+            capacity: 2,
+            policy,
+        }))
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue = make_queue(OverflowPolicy::DropOldest);
+
+        for i in 0..3u8 {
+            queue.push(Bytes::from(vec![i])).await.unwrap();
+        }
+
+        let stats = queue.stats();
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.dropped, 1);
+
+        let batch = queue.pop_all().await.unwrap();
+        assert_eq!(batch, vec![Bytes::from(vec![1]), Bytes::from(vec![2])]);
+    }
+
+    #[tokio::test]
+    async fn drop_peer() {
+        let queue = make_queue(OverflowPolicy::DropPeer);
+
+        queue.push(Bytes::new()).await.unwrap();
+        queue.push(Bytes::new()).await.unwrap();
+
+        assert!(matches!(
+            queue.push(Bytes::new()).await,
+            Err(Error::QueueFull)
+        ));
+        assert!(queue.pop_all().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let queue = make_queue(OverflowPolicy::Block);
+
+        queue.push(Bytes::new()).await.unwrap();
+        queue.push(Bytes::new()).await.unwrap();
+
+        let q2 = queue.clone();
+        let producer = tokio::spawn(async move { q2.push(Bytes::new()).await });
+
+        tokio::task::yield_now().await;
+        assert!(!producer.is_finished());
+
+        assert_eq!(queue.pop_all().await.unwrap().len(), 2);
+        producer.await.unwrap().unwrap();
+
+        let stats = queue.stats();
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.max_depth, 2);
+    }
+
+    #[tokio::test]
+    async fn try_push() {
+        // Never waits and never exceeds the capacity
+        for policy in [OverflowPolicy::Block, OverflowPolicy::DropPeer] {
+            let queue = make_queue(policy);
+            queue.try_push(Bytes::new()).unwrap();
+            queue.try_push(Bytes::new()).unwrap();
+            assert!(matches!(
+                queue.try_push(Bytes::new()),
+                Err(Error::QueueFull)
+            ));
+            assert_eq!(queue.stats().max_depth, 2);
+            assert!(queue.pop_all().await.is_none());
+        }
+
+        let queue = make_queue(OverflowPolicy::DropOldest);
+        for i in 0..3u8 {
+            queue.try_push(Bytes::from(vec![i])).unwrap();
+        }
+        assert_eq!(queue.stats().depth, 2);
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn finish() {
+        let queue = make_queue(OverflowPolicy::Block);
+
+        queue.push(Bytes::new()).await.unwrap();
+        queue.finish();
+
+        assert!(matches!(
+            queue.push(Bytes::new()).await,
+            Err(Error::PeerDisconnected)
+        ));
+
+        // Queued messages are still delivered
+        assert_eq!(queue.pop_all().await.unwrap().len(), 1);
+        assert!(queue.pop_all().await.is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::protocol::EpochId;
+use crate::{Epoch, Error, OpTrait};
+
+/// Appends sealed epochs to a file so they are still available after the server stopped
+///
+/// The file is a sequence of bincode-encoded `(EpochId, Epoch)` pairs.
+pub struct EpochStorage {
+    writer: BufWriter<File>,
+}
+
+impl EpochStorage {
+    /// Creates the file (or truncates it if it already exists)
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn append<OpType: OpTrait>(
+        &mut self,
+        identifier: EpochId,
+        epoch: &Epoch<OpType>,
+    ) -> Result<(), Error> {
+        bincode::serialize_into(&mut self.writer, &(identifier, epoch))
+            .map_err(|err| Error::Serialization(err.to_string()))?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Reads all epochs written by an `EpochStorage`
+pub fn read_epochs<OpType: OpTrait + DeserializeOwned>(
+    path: &Path,
+) -> Result<Vec<(EpochId, Epoch<OpType>)>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut epochs = vec![];
+
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(entry) => epochs.push(entry),
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                err => return Err(Error::Deserialization(err.to_string())),
+            },
+        }
+    }
+
+    Ok(epochs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_epochs, EpochStorage};
+    use crate::{generate_key_pair, to_account_id, Epoch, Ledger, TestOperation, Transaction};
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("epochs-{}.bin", std::process::id()));
+        let ledger = Ledger::<TestOperation>::default();
+        let (skey, pkey) = generate_key_pair();
+        let account = to_account_id(&pkey);
+
+        let mut storage = EpochStorage::create(&path).unwrap();
+
+        for identifier in 0..3 {
+            ledger.create_new_epoch(identifier, 100).unwrap();
+            ledger
+                .insert(Transaction::new(
+                    account,
+                    TestOperation::Empty {},
+                    skey.clone(),
+                ))
+                .unwrap();
+            ledger.seal_epoch(identifier, 200).unwrap();
+
+            storage
+                .append(identifier, &ledger.get_epoch(identifier).unwrap())
+                .unwrap();
+        }
+
+        let epochs = read_epochs::<TestOperation>(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(epochs.len(), 3);
+        for (pos, (identifier, epoch)) in epochs.iter().enumerate() {
+            let expected: Epoch<TestOperation> = ledger.get_epoch(*identifier).unwrap();
+
+            assert_eq!(pos as u32, *identifier);
+            assert_eq!(epoch.size(), 1);
+            assert_eq!(epoch.get_statistics(), expected.get_statistics());
+        }
+    }
+}
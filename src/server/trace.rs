@@ -0,0 +1,406 @@
+//! Structured record of everything that happens on the server, for offline analysis
+//!
+//! Every entry carries a monotonic timestamp (in microseconds since the trace started).
+//! Traces are written either as JSON lines or as CSV with one column per event field.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use log::error;
+
+use crate::protocol::{EpochId, RejectReason, SequenceNumber};
+use crate::transactions::{Transaction, TransactionId};
+use crate::{AccountId, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceFormat {
+    /// One JSON object per line
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl TraceFormat {
+    /// CSV for files ending in `.csv`, JSON lines otherwise
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            Self::Csv
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Something that happened on the server
+///
+/// Broadcasts are identified by their sequence number, which is also recorded
+/// for the event that caused them (e.g., a committed transaction).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// A client submitted a transaction (the peer is None for the HTTP gateway)
+    TransactionReceived {
+        #[serde(with = "hex_id")]
+        transaction: TransactionId,
+        source: AccountId,
+        byte_size: u64,
+        cost: u64,
+        peer: Option<u32>,
+        /// The signed transaction (bincode-encoded), so that it can be replayed
+        #[serde(with = "hex_bytes")]
+        data: Vec<u8>,
+    },
+    /// The callback (and, if the peer authenticated, the source account) was checked
+    TransactionValidated {
+        #[serde(with = "hex_id")]
+        transaction: TransactionId,
+        valid: bool,
+    },
+    TransactionCommitted {
+        #[serde(with = "hex_id")]
+        transaction: TransactionId,
+        epoch: EpochId,
+        sequence: SequenceNumber,
+    },
+    TransactionRejected {
+        #[serde(with = "hex_id")]
+        transaction: TransactionId,
+        reason: RejectReason,
+    },
+    /// A broadcast was added to the outbound queue of a peer (it might not have been sent yet)
+    BroadcastQueued { peer: u32, sequence: SequenceNumber },
+    EpochStarted {
+        epoch: EpochId,
+        sequence: SequenceNumber,
+    },
+    EpochSealed {
+        epoch: EpochId,
+        sequence: SequenceNumber,
+        num_transactions: usize,
+    },
+}
+
+impl TraceEvent {
+    /// A client submitted the given transaction (the peer is None for the HTTP gateway)
+    pub fn transaction_received<OpType: Serialize + Debug>(
+        transaction: &Transaction<OpType>,
+        peer: Option<u32>,
+    ) -> Self {
+        Self::TransactionReceived {
+            transaction: transaction.get_id(),
+            source: *transaction.get_source(),
+            byte_size: transaction.byte_size(),
+            cost: transaction.get_cost(),
+            peer,
+            data: bincode::serialize(transaction).unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Microseconds since the trace was started
+    pub time_us: u64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// All fields of all events, in the order they appear in CSV files
+const CSV_COLUMNS: &[&str] = &[
+    "time_us",
+    "event",
+    "transaction",
+    "source",
+    "byte_size",
+    "cost",
+    "peer",
+    "valid",
+    "epoch",
+    "sequence",
+    "num_transactions",
+    "reason",
+    "data",
+];
+
+mod hex_bytes {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    pub fn decode(hex: &str) -> Option<Vec<u8>> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|pos| u8::from_str_radix(&hex[pos..pos + 2], 16).ok())
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        decode(&hex).ok_or_else(|| D::Error::custom(format!("Invalid hex string: {hex}")))
+    }
+}
+
+mod hex_id {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::hex_bytes;
+    use crate::transactions::TransactionId;
+
+    pub fn serialize<S: Serializer>(id: &TransactionId, serializer: S) -> Result<S::Ok, S::Error> {
+        hex_bytes::serialize(id, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<TransactionId, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+
+        hex_bytes::decode(&hex)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| D::Error::custom(format!("Invalid transaction id: {hex}")))
+    }
+}
+
+/// Writes trace entries to a file
+pub struct TraceWriter {
+    format: TraceFormat,
+    output: BufWriter<File>,
+    start: Instant,
+}
+
+impl TraceWriter {
+    /// Creates (or truncates) the file; timestamps are relative to this call
+    pub fn create(path: &Path, format: TraceFormat) -> Result<Self, Error> {
+        let mut output = BufWriter::new(File::create(path)?);
+
+        if format == TraceFormat::Csv {
+            writeln!(output, "{}", CSV_COLUMNS.join(","))?;
+        }
+
+        Ok(Self {
+            format,
+            output,
+            start: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, event: TraceEvent) -> Result<(), Error> {
+        let entry = TraceEntry {
+            time_us: self.start.elapsed().as_micros() as u64,
+            event,
+        };
+
+        let line =
+            serde_json::to_string(&entry).map_err(|err| Error::Serialization(err.to_string()))?;
+
+        if self.format == TraceFormat::Json {
+            writeln!(self.output, "{line}")?;
+            return Ok(());
+        }
+
+        let fields: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&line).map_err(|err| Error::Serialization(err.to_string()))?;
+
+        let row: Vec<String> = CSV_COLUMNS
+            .iter()
+            .map(|column| match fields.get(*column) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            })
+            .collect();
+
+        writeln!(self.output, "{}", row.join(","))?;
+        Ok(())
+    }
+}
+
+/// The trace of a server; does nothing until a writer is set
+#[derive(Default)]
+pub struct EventTrace {
+    writer: Mutex<Option<TraceWriter>>,
+}
+
+impl EventTrace {
+    pub fn start(&self, writer: TraceWriter) {
+        *self.writer.lock().unwrap() = Some(writer);
+    }
+
+    /// Appends an event to the trace (if enabled)
+    ///
+    /// The timestamp is taken while holding the lock, so entries are in chronological order.
+    /// If writing fails, tracing is disabled.
+    pub fn record(&self, event: TraceEvent) {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Some(inner) = writer.as_mut() {
+            if let Err(err) = inner.write(event) {
+                error!("Failed to write trace; disabling it: {err}");
+                *writer = None;
+            }
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Some(writer) = self.writer.lock().unwrap().as_mut() {
+            if let Err(err) = writer.output.flush() {
+                error!("Failed to flush trace: {err}");
+            }
+        }
+    }
+}
+
+/// Reads a trace written in the given format
+pub fn read_trace(path: &Path, format: TraceFormat) -> Result<Vec<TraceEntry>, Error> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let mut entries = Vec::new();
+
+    if format == TraceFormat::Csv {
+        // Skip the header
+        lines.next().transpose()?;
+    }
+
+    for line in lines {
+        let line = line?;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = match format {
+            TraceFormat::Json => serde_json::from_str(&line)
+                .map_err(|err| Error::Deserialization(err.to_string()))?,
+            TraceFormat::Csv => parse_csv_row(&line)?,
+        };
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn parse_csv_row(line: &str) -> Result<TraceEntry, Error> {
+    let cells: Vec<&str> = line.split(',').collect();
+
+    if cells.len() != CSV_COLUMNS.len() {
+        return Err(Error::Deserialization(format!("Invalid trace row: {line}")));
+    }
+
+    let mut fields = serde_json::Map::new();
+
+    for (column, cell) in CSV_COLUMNS.iter().zip(cells) {
+        if cell.is_empty() {
+            continue;
+        }
+
+        // Transaction ids, reasons, and transaction data are the only strings
+        let value = if matches!(*column, "transaction" | "data") {
+            cell.to_string().into()
+        } else if let Ok(number) = cell.parse::<u64>() {
+            number.into()
+        } else if let Ok(flag) = cell.parse::<bool>() {
+            flag.into()
+        } else {
+            cell.to_string().into()
+        };
+
+        fields.insert(column.to_string(), value);
+    }
+
+    serde_json::from_value(fields.into()).map_err(|err| Error::Deserialization(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_trace, EventTrace, TraceEvent, TraceFormat, TraceWriter};
+    use crate::protocol::RejectReason;
+
+    #[test]
+    fn round_trip() {
+        let events = vec![
+            TraceEvent::EpochStarted {
+                epoch: 0,
+                sequence: 0,
+            },
+            TraceEvent::TransactionReceived {
+                transaction: [0x1f; 32],
+                source: 42,
+                byte_size: 300,
+                cost: 1,
+                peer: Some(3),
+                data: vec![0x01, 0xab, 0x00],
+            },
+            TraceEvent::TransactionValidated {
+                transaction: [0x1f; 32],
+                valid: true,
+            },
+            TraceEvent::TransactionCommitted {
+                transaction: [0x1f; 32],
+                epoch: 0,
+                sequence: 1,
+            },
+            TraceEvent::BroadcastQueued {
+                peer: 3,
+                sequence: 1,
+            },
+            TraceEvent::TransactionReceived {
+                transaction: [0x20; 32],
+                source: 7,
+                byte_size: 300,
+                cost: 1,
+                peer: None,
+                data: vec![0x12; 4],
+            },
+            TraceEvent::TransactionRejected {
+                transaction: [0x20; 32],
+                reason: RejectReason::Expired,
+            },
+            TraceEvent::EpochSealed {
+                epoch: 0,
+                sequence: 2,
+                num_transactions: 1,
+            },
+        ];
+
+        for format in [TraceFormat::Json, TraceFormat::Csv] {
+            let path =
+                std::env::temp_dir().join(format!("trace-{}.{format:?}", std::process::id()));
+
+            let trace = EventTrace::default();
+            trace.start(TraceWriter::create(&path, format).unwrap());
+
+            for event in &events {
+                trace.record(event.clone());
+            }
+            trace.flush();
+
+            let entries = read_trace(&path, format).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(
+                entries.iter().map(|e| e.event.clone()).collect::<Vec<_>>(),
+                events
+            );
+            assert!(entries.windows(2).all(|w| w[0].time_us <= w[1].time_us));
+        }
+    }
+}
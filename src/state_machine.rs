@@ -0,0 +1,50 @@
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha512};
+
+use crate::{Epoch, OpTrait, Transaction};
+
+pub type StateError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A state machine that is driven by the ledger while the application keeps a (typed) handle to it
+///
+/// Any `Arc<Mutex<S>>` with `S: StateMachine` can be converted into this.
+pub type SharedStateMachine<OpType> = Arc<Mutex<dyn StateMachine<OpType>>>;
+
+/// Application state that is derived from the ledger
+///
+/// The state only depends on the order of transactions in the ledger, so every node
+/// that applies the same epochs ends up with the same snapshot. Transactions for which
+/// `apply` fails stay in the ledger but must not modify the state.
+pub trait StateMachine<OpType: OpTrait>: Send {
+    fn apply(&mut self, tx: &Transaction<OpType>) -> Result<(), StateError>;
+
+    /// Serializes the current state
+    ///
+    /// This must be deterministic (e.g., do not serialize a HashMap directly),
+    /// so that snapshots of different nodes can be compared.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replaces the current state with a snapshot
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError>;
+
+    /// Hash of the current snapshot
+    fn digest(&self) -> Vec<u8> {
+        Sha512::digest(self.snapshot()).to_vec()
+    }
+
+    /// Applies all transactions of an epoch in order
+    ///
+    /// Returns the number of transactions that were skipped because they are invalid.
+    fn replay_epoch(&mut self, epoch: &Epoch<OpType>) -> usize {
+        let mut num_failed = 0;
+
+        for tx in epoch.get_transactions() {
+            if self.apply(tx).is_err() {
+                num_failed += 1;
+            }
+        }
+
+        num_failed
+    }
+}
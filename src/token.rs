@@ -0,0 +1,561 @@
+//! A simple fungible token that can be used as a reference application
+//!
+//! Accounts have to be registered with `TxPayload::CreateAccount` before they can
+//! send tokens. New tokens can only be minted by a fixed set of minter accounts.
+//! Accounts and their credentials are managed by an `IdentityRegistry`, so transactions
+//! are authorized exactly as the ledger authorizes them (including multisig accounts).
+//!
+//! `TokenState` is deterministic, so clients can derive the same balances as the
+//! server by replaying all epochs. Transactions that are invalid at the point they
+//! appear in the ledger (e.g., because of a concurrent transfer) are skipped.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::EpochId;
+use crate::{
+    AccountId, Epoch, IdentityError, IdentityRegistry, Ledger, StateError, StateMachine,
+    Transaction, TxPayload,
+};
+
+/// The token does not know which epoch a transaction is part of, so all credentials are
+/// recorded for the same epoch (only the current ones are used for authorization)
+const IDENTITY_EPOCH: EpochId = 0;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum TokenOperation {
+    /// Creates new tokens (only allowed for minters)
+    Mint { to: AccountId, amount: u64 },
+    /// Moves tokens from the source of the transaction to another account
+    Transfer { to: AccountId, amount: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// The transaction is not authorized or changes an account in an invalid way
+    Identity(IdentityError),
+    UnknownAccount(AccountId),
+    NotAMinter(AccountId),
+    InsufficientBalance {
+        available: u64,
+        required: u64,
+    },
+    Overflow,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Identity(err) => write!(f, "{err}"),
+            Self::UnknownAccount(id) => write!(f, "No such account: {id}"),
+            Self::NotAMinter(id) => write!(f, "Account {id} is not allowed to mint tokens"),
+            Self::InsufficientBalance {
+                available,
+                required,
+            } => write!(
+                f,
+                "Insufficient balance: {available} available but {required} required"
+            ),
+            Self::Overflow => write!(f, "Balance overflow"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<IdentityError> for TokenError {
+    fn from(err: IdentityError) -> Self {
+        Self::Identity(err)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TokenState {
+    minters: HashSet<AccountId>,
+    identities: IdentityRegistry,
+    balances: HashMap<AccountId, u64>,
+}
+
+impl TokenState {
+    pub fn new(minters: impl IntoIterator<Item = AccountId>) -> Self {
+        Self {
+            minters: minters.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Computes the state from all transactions in the ledger
+    pub fn from_ledger(
+        ledger: &Ledger<TokenOperation>,
+        minters: impl IntoIterator<Item = AccountId>,
+    ) -> Self {
+        let mut state = Self::new(minters);
+
+        for identifier in 0..ledger.num_epochs() as EpochId {
+            if let Ok(epoch) = ledger.get_epoch(identifier) {
+                state.apply_epoch(&epoch);
+            }
+        }
+
+        state
+    }
+
+    pub fn balance_of(&self, account: &AccountId) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    /// Was the account created (and not deactivated since)?
+    pub fn has_account(&self, account: &AccountId) -> bool {
+        self.identities
+            .get(account)
+            .is_some_and(|identity| identity.is_active())
+    }
+
+    /// Applies all transactions of an epoch in order
+    ///
+    /// Returns the position and error of all transactions that were skipped.
+    pub fn apply_epoch(&mut self, epoch: &Epoch<TokenOperation>) -> Vec<(usize, TokenError)> {
+        let mut failed = Vec::new();
+
+        for (pos, tx) in epoch.get_transactions().iter().enumerate() {
+            if let Err(err) = self.apply(tx) {
+                failed.push((pos, err));
+            }
+        }
+
+        failed
+    }
+
+    /// Checks whether a transaction would be valid without changing the state
+    pub fn check(&self, tx: &Transaction<TokenOperation>) -> Result<(), TokenError> {
+        self.plan(tx).map(|_| ())
+    }
+
+    /// Applies a single transaction, or leaves the state unchanged if it is invalid
+    pub fn apply(&mut self, tx: &Transaction<TokenOperation>) -> Result<(), TokenError> {
+        match self.plan(tx)? {
+            Change::Identity => {
+                self.identities.apply(tx, IDENTITY_EPOCH)?;
+            }
+            Change::Mint { to, amount } => {
+                *self.balances.entry(to).or_default() += amount;
+            }
+            Change::Transfer { from, to, amount } => {
+                *self.balances.entry(from).or_default() -= amount;
+                *self.balances.entry(to).or_default() += amount;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates a transaction and determines how it changes the state
+    ///
+    /// All checks (including overflows) happen here, so applying the change cannot fail.
+    fn plan(&self, tx: &Transaction<TokenOperation>) -> Result<Change, TokenError> {
+        let source = *tx.get_source();
+
+        let TxPayload::Operation { operation } = tx.get_payload() else {
+            self.identities.check(tx)?;
+            return Ok(Change::Identity);
+        };
+
+        // Deactivated accounts keep their balance, but it is frozen
+        self.identities.authorize(tx)?;
+
+        match *operation {
+            TokenOperation::Mint { to, amount } => {
+                if !self.minters.contains(&source) {
+                    return Err(TokenError::NotAMinter(source));
+                }
+
+                self.balance_of(&to)
+                    .checked_add(amount)
+                    .ok_or(TokenError::Overflow)?;
+
+                Ok(Change::Mint { to, amount })
+            }
+            TokenOperation::Transfer { to, amount } => {
+                if !self.has_account(&to) {
+                    return Err(TokenError::UnknownAccount(to));
+                }
+
+                let available = self.balance_of(&source);
+                if available < amount {
+                    return Err(TokenError::InsufficientBalance {
+                        available,
+                        required: amount,
+                    });
+                }
+
+                if to != source && self.balance_of(&to).checked_add(amount).is_none() {
+                    return Err(TokenError::Overflow);
+                }
+
+                Ok(Change::Transfer {
+                    from: source,
+                    to,
+                    amount,
+                })
+            }
+        }
+    }
+}
+
+/// The effect of a valid transaction on the state
+enum Change {
+    /// Creates an account or changes its credentials (see `IdentityRegistry::apply`)
+    Identity,
+    Mint {
+        to: AccountId,
+        amount: u64,
+    },
+    Transfer {
+        from: AccountId,
+        to: AccountId,
+        amount: u64,
+    },
+}
+
+/// Sorted version of the state, so that it serializes deterministically
+#[derive(Serialize, Deserialize)]
+struct TokenSnapshot {
+    minters: BTreeSet<AccountId>,
+    identities: IdentityRegistry,
+    balances: BTreeMap<AccountId, u64>,
+}
+
+impl StateMachine<TokenOperation> for TokenState {
+    fn apply(&mut self, tx: &Transaction<TokenOperation>) -> Result<(), StateError> {
+        Ok(TokenState::apply(self, tx)?)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let snapshot = TokenSnapshot {
+            minters: self.minters.iter().copied().collect(),
+            identities: self.identities.clone(),
+            balances: self.balances.iter().map(|(id, b)| (*id, *b)).collect(),
+        };
+
+        bincode::serialize(&snapshot).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+        let snapshot: TokenSnapshot = bincode::deserialize(snapshot)?;
+
+        self.minters = snapshot.minters.into_iter().collect();
+        self.identities = snapshot.identities;
+        self.balances = snapshot.balances.into_iter().collect();
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+mod callback {
+    use std::sync::{Arc, Mutex};
+
+    use super::{TokenOperation, TokenState};
+    use crate::server::Callback;
+    use crate::{AccountId, SharedStateMachine, Transaction};
+
+    /// Only admits transactions that are valid given all committed ones
+    ///
+    /// Validation does not change the state; transactions are only applied once they are
+    /// committed, through the state machine of the server (see `state_machine`). If two
+    /// concurrent transfers spend the same tokens, both may be admitted, but the second one
+    /// is skipped when it is applied (as it would be by any client replaying the ledger).
+    pub struct TokenCallback {
+        state: Arc<Mutex<TokenState>>,
+    }
+
+    impl TokenCallback {
+        pub fn new(minters: impl IntoIterator<Item = AccountId>) -> Self {
+            Self {
+                state: Arc::new(Mutex::new(TokenState::new(minters))),
+            }
+        }
+
+        /// Must be set as the state machine of the server (`ServerConfigBuilder::state_machine`)
+        pub fn state_machine(&self) -> SharedStateMachine<TokenOperation> {
+            self.state.clone()
+        }
+
+        pub fn balance_of(&self, account: &AccountId) -> u64 {
+            self.state.lock().unwrap().balance_of(account)
+        }
+    }
+
+    impl Callback<TokenOperation> for TokenCallback {
+        fn validate_transaction(&self, tx: &Transaction<TokenOperation>) -> bool {
+            let state = self.state.lock().unwrap();
+
+            match state.check(tx) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::debug!("Rejected token transaction: {err}");
+                    false
+                }
+            }
+        }
+
+        fn notify_new_transaction(&self, _: &Transaction<TokenOperation>) {}
+    }
+}
+
+#[cfg(feature = "server")]
+pub use callback::TokenCallback;
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenError, TokenOperation, TokenState};
+    use crate::{
+        generate_key_pair, to_account_id, IdentityError, Ledger, StateMachine, Transaction,
+    };
+
+    #[test]
+    fn transfer() {
+        let (minter_skey, minter_pkey) = generate_key_pair();
+        let (user_skey, user_pkey) = generate_key_pair();
+        let minter = to_account_id(&minter_pkey);
+        let user = to_account_id(&user_pkey);
+
+        let ledger = Ledger::default();
+        ledger.create_new_epoch(0, 0).unwrap();
+
+        let txs = vec![
+            Transaction::new_create_account(minter_pkey, minter_skey.clone()),
+            Transaction::new_create_account(user_pkey, user_skey.clone()),
+            Transaction::new(
+                minter,
+                TokenOperation::Mint {
+                    to: minter,
+                    amount: 100,
+                },
+                minter_skey.clone(),
+            ),
+            Transaction::new(
+                user,
+                TokenOperation::Mint {
+                    to: user,
+                    amount: 100,
+                },
+                user_skey.clone(),
+            ),
+            Transaction::new(
+                minter,
+                TokenOperation::Transfer {
+                    to: user,
+                    amount: 30,
+                },
+                minter_skey.clone(),
+            ),
+            Transaction::new(
+                user,
+                TokenOperation::Transfer {
+                    to: minter,
+                    amount: 31,
+                },
+                user_skey,
+            ),
+            // Signed by the wrong key
+            Transaction::new(
+                user,
+                TokenOperation::Transfer {
+                    to: minter,
+                    amount: 1,
+                },
+                minter_skey,
+            ),
+        ];
+
+        for tx in txs {
+            ledger.insert(tx).unwrap();
+        }
+
+        let mut state = TokenState::new([minter]);
+        let failed = state.apply_epoch(&ledger.get_epoch(0).unwrap());
+
+        assert_eq!(
+            failed,
+            vec![
+                (3, TokenError::NotAMinter(user)),
+                (
+                    5,
+                    TokenError::InsufficientBalance {
+                        available: 30,
+                        required: 31
+                    }
+                ),
+                (6, TokenError::Identity(IdentityError::InvalidSignature)),
+            ]
+        );
+
+        assert_eq!(state.balance_of(&minter), 70);
+        assert_eq!(state.balance_of(&user), 30);
+
+        // Replaying yields the same result
+        let replayed = TokenState::from_ledger(&ledger, [minter]);
+        assert_eq!(replayed.balance_of(&minter), 70);
+        assert_eq!(replayed.balance_of(&user), 30);
+        assert_eq!(replayed.digest(), state.digest());
+
+        let mut restored = TokenState::default();
+        restored.restore(&state.snapshot()).unwrap();
+        assert_eq!(restored.balance_of(&user), 30);
+        assert_eq!(restored.digest(), state.digest());
+    }
+
+    #[test]
+    fn rotation_and_deactivation() {
+        let (first_skey, first_pkey) = generate_key_pair();
+        let (second_skey, second_pkey) = generate_key_pair();
+        let account = to_account_id(&first_pkey);
+
+        let mut state = TokenState::new([account]);
+
+        let create = Transaction::new_create_account(first_pkey.clone(), first_skey.clone());
+        state.apply(&create).unwrap();
+
+        let rotate = Transaction::new_rotate_key(account, second_pkey, 0, first_skey.clone());
+        state.apply(&rotate).unwrap();
+        let rotate_back = Transaction::new_rotate_key(account, first_pkey, 1, second_skey);
+        state.apply(&rotate_back).unwrap();
+
+        // Signed by the current key, but rotations cannot be replayed
+        assert_eq!(
+            state.apply(&rotate),
+            Err(TokenError::Identity(IdentityError::InvalidSequence {
+                expected: 2,
+                received: 0
+            }))
+        );
+
+        let mint = Transaction::new(
+            account,
+            TokenOperation::Mint {
+                to: account,
+                amount: 10,
+            },
+            first_skey.clone(),
+        );
+        state.apply(&mint).unwrap();
+
+        state
+            .apply(&Transaction::new_deactivate_account(account, first_skey))
+            .unwrap();
+
+        // Creating the account again would unfreeze its balance
+        assert_eq!(
+            state.apply(&create),
+            Err(TokenError::Identity(IdentityError::Deactivated(account)))
+        );
+        assert!(!state.has_account(&account));
+        assert_eq!(state.balance_of(&account), 10);
+
+        let mut restored = TokenState::default();
+        restored.restore(&state.snapshot()).unwrap();
+        assert_eq!(restored.digest(), state.digest());
+        assert_eq!(
+            restored.apply(&create),
+            Err(TokenError::Identity(IdentityError::Deactivated(account)))
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn validated_but_rejected() {
+        use std::sync::Arc;
+
+        use futures::{SinkExt, StreamExt};
+
+        use super::TokenCallback;
+        use crate::encoding::Encoding;
+        use crate::protocol::{Message, RejectReason};
+        use crate::server::{start_server, Callback, ServerConfig};
+        use crate::{TimeBound, Validity};
+
+        let encoding = Encoding::Bincode;
+
+        let (minter_skey, minter_pkey) = generate_key_pair();
+        let (user_skey, user_pkey) = generate_key_pair();
+        let minter = to_account_id(&minter_pkey);
+        let user = to_account_id(&user_pkey);
+
+        let callback = Arc::new(TokenCallback::new([minter]));
+        let config = ServerConfig::<TokenOperation>::builder(callback.clone())
+            .in_memory()
+            .throughput(10_000.0)
+            .latency_ms(0)
+            .state_machine(callback.state_machine())
+            .build()
+            .unwrap();
+        let server = start_server(config).await.unwrap();
+        let mut client = server.connect(encoding).await.unwrap();
+
+        let setup = vec![
+            Transaction::new_create_account(minter_pkey, minter_skey.clone()),
+            Transaction::new_create_account(user_pkey, user_skey),
+            Transaction::new(
+                minter,
+                TokenOperation::Mint {
+                    to: minter,
+                    amount: 100,
+                },
+                minter_skey.clone(),
+            ),
+        ];
+        let num_setup = setup.len();
+
+        for transaction in setup {
+            let data = encoding
+                .encode(&Message::TransactionRequest { transaction })
+                .unwrap();
+            client.send(data.into()).await.unwrap();
+        }
+
+        server.wait_for_transactions(num_setup).await;
+        assert_eq!(callback.balance_of(&minter), 100);
+
+        // Valid for the token, but the ledger will not admit it yet
+        let not_yet_valid = Validity {
+            not_before: Some(TimeBound::Epoch(5)),
+            not_after: None,
+        };
+        let transaction = Transaction::new_with_validity(
+            minter,
+            TokenOperation::Transfer {
+                to: user,
+                amount: 30,
+            },
+            1,
+            not_yet_valid,
+            minter_skey,
+        );
+        assert!(callback.validate_transaction(&transaction));
+
+        let data = encoding
+            .encode(&Message::TransactionRequest { transaction })
+            .unwrap();
+        client.send(data.into()).await.unwrap();
+
+        loop {
+            let data = client.next().await.unwrap().unwrap();
+
+            if let Message::<TokenOperation>::TransactionRejected { reason, .. } =
+                encoding.decode(&data).unwrap()
+            {
+                assert_eq!(reason, RejectReason::NotYetValid);
+                break;
+            }
+        }
+
+        // Neither validation nor the rejection changed any balance
+        assert_eq!(callback.balance_of(&minter), 100);
+        assert_eq!(callback.balance_of(&user), 0);
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -1,70 +1,324 @@
 use std::fmt::Debug;
 
 use bytes::Bytes;
-use rsa::pss::SigningKey;
-use rsa::signature::{RandomizedSigner, SignatureEncoding};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256, Sha512};
+use sha2::{Digest, Sha256};
 
-use crate::crypto_helper::{to_account_id, AccountId, PrivateKey, PublicKey};
+use crate::crypto_helper::{sign, to_account_id, verify, AccountId, PrivateKey, PublicKey};
+use crate::identity::MultisigPolicy;
+use crate::protocol::{EpochId, RejectReason};
+use crate::Error;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum TxPayload<OpType> {
-    CreateAccount { public_key: PublicKey },
-    Operation { operation: OpType },
+    CreateAccount {
+        public_key: PublicKey,
+    },
+    /// From now on, transactions of the source account must be signed by k-of-n other accounts
+    SetMultisigPolicy {
+        policy: MultisigPolicy,
+    },
+    /// Replaces the key of the source account (signed with the current key)
+    RotateKey {
+        new_public_key: PublicKey,
+        /// Number of earlier rotations of the account, so that a rotation cannot be replayed
+        sequence: u64,
+    },
+    /// No further transactions of the source account will be authorized
+    DeactivateAccount,
+    Operation {
+        operation: OpType,
+    },
+}
+
+/// Proof that the source account issued a transaction
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Authorization {
+    /// Signed by the key of the source account
+    Signature(Bytes),
+    /// Signed by (some of) the accounts listed in the multisig policy of the source account
+    Multisig(Vec<(AccountId, Bytes)>),
+}
+
+/// SHA-256 hash of a serialized transaction
+pub type TransactionId = [u8; 32];
+
+/// The cost of a transaction that did not declare one
+pub const DEFAULT_TX_COST: u64 = 1;
+
+/// A point in time, either as an epoch or as a UNIX timestamp (in seconds)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBound {
+    Epoch(EpochId),
+    Timestamp(i64),
+}
+
+impl TimeBound {
+    /// Has this point in time been reached in the given epoch at the given time?
+    fn is_reached(&self, epoch: EpochId, timestamp: i64) -> bool {
+        match self {
+            Self::Epoch(bound) => epoch >= *bound,
+            Self::Timestamp(bound) => timestamp >= *bound,
+        }
+    }
+}
+
+/// Restricts when a transaction may be committed (both bounds are inclusive)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: Option<TimeBound>,
+    pub not_after: Option<TimeBound>,
+}
+
+impl Validity {
+    /// A transaction that expires after the given epoch
+    pub fn until_epoch(epoch: EpochId) -> Self {
+        Self {
+            not_before: None,
+            not_after: Some(TimeBound::Epoch(epoch)),
+        }
+    }
+
+    /// A transaction that expires after the given UNIX timestamp
+    pub fn until_timestamp(timestamp: i64) -> Self {
+        Self {
+            not_before: None,
+            not_after: Some(TimeBound::Timestamp(timestamp)),
+        }
+    }
+
+    /// Can the transaction be committed to the given epoch at the given time?
+    pub fn check(&self, epoch: EpochId, timestamp: i64) -> Result<(), RejectReason> {
+        if let Some(bound) = &self.not_before {
+            if !bound.is_reached(epoch, timestamp) {
+                return Err(RejectReason::NotYetValid);
+            }
+        }
+
+        if let Some(bound) = &self.not_after {
+            let expired = match bound {
+                TimeBound::Epoch(bound) => epoch > *bound,
+                TimeBound::Timestamp(bound) => timestamp > *bound,
+            };
+
+            if expired {
+                return Err(RejectReason::Expired);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction<OpType> {
     source: AccountId,
     payload: TxPayload<OpType>,
-    signature: Bytes,
+    /// Declared cost (or gas) of the transaction; counts against the capacity of an epoch
+    cost: u64,
+    validity: Validity,
+    authorization: Authorization,
 }
 
 impl<Operation: Serialize + Debug> Transaction<Operation> {
     pub fn new_create_account(public_key: PublicKey, private_key: PrivateKey) -> Self {
         let source = to_account_id(&public_key);
         let payload = TxPayload::CreateAccount { public_key };
-        let data = bincode::serialize(&payload).unwrap();
 
-        let mut hasher = Sha512::new();
-        hasher.update(&data[..]);
-        let hash = hasher.finalize();
+        Self::new_signed(
+            source,
+            payload,
+            DEFAULT_TX_COST,
+            Validity::default(),
+            &private_key,
+        )
+    }
+
+    pub fn new(source: AccountId, operation: Operation, private_key: PrivateKey) -> Self {
+        Self::new_with_cost(source, operation, DEFAULT_TX_COST, private_key)
+    }
+
+    pub fn new_with_cost(
+        source: AccountId,
+        operation: Operation,
+        cost: u64,
+        private_key: PrivateKey,
+    ) -> Self {
+        Self::new_with_validity(source, operation, cost, Validity::default(), private_key)
+    }
 
-        let mut rng = rand::thread_rng();
-        let signing_key = SigningKey::<Sha256>::new(private_key);
-        let sig = signing_key.sign_with_rng(&mut rng, &hash);
+    /// Changes how transactions of the source account are authorized
+    pub fn new_set_multisig_policy(
+        source: AccountId,
+        policy: MultisigPolicy,
+        private_key: PrivateKey,
+    ) -> Self {
+        let payload = TxPayload::SetMultisigPolicy { policy };
+        Self::new_signed(
+            source,
+            payload,
+            DEFAULT_TX_COST,
+            Validity::default(),
+            &private_key,
+        )
+    }
 
+    /// `sequence` must be the number of times the key of the account was rotated before
+    pub fn new_rotate_key(
+        source: AccountId,
+        new_public_key: PublicKey,
+        sequence: u64,
+        private_key: PrivateKey,
+    ) -> Self {
+        let payload = TxPayload::RotateKey {
+            new_public_key,
+            sequence,
+        };
+        Self::new_signed(
+            source,
+            payload,
+            DEFAULT_TX_COST,
+            Validity::default(),
+            &private_key,
+        )
+    }
+
+    pub fn new_deactivate_account(source: AccountId, private_key: PrivateKey) -> Self {
+        Self::new_signed(
+            source,
+            TxPayload::DeactivateAccount,
+            DEFAULT_TX_COST,
+            Validity::default(),
+            &private_key,
+        )
+    }
+
+    /// Creates a transaction for an account with a multisig policy
+    ///
+    /// Signatures must be added using `add_signature` until the threshold is met.
+    pub fn new_multisig(
+        source: AccountId,
+        payload: TxPayload<Operation>,
+        cost: u64,
+        validity: Validity,
+    ) -> Self {
         Self {
             source,
             payload,
-            signature: sig.to_vec().into(),
+            cost,
+            validity,
+            authorization: Authorization::Multisig(Vec::new()),
         }
     }
 
-    pub fn new(source: AccountId, operation: Operation, private_key: PrivateKey) -> Self {
+    /// Creates a transaction that is dropped if it cannot be committed within the given window
+    pub fn new_with_validity(
+        source: AccountId,
+        operation: Operation,
+        cost: u64,
+        validity: Validity,
+        private_key: PrivateKey,
+    ) -> Self {
         let payload = TxPayload::Operation { operation };
-        let data = bincode::serialize(&payload).unwrap();
-
-        let mut hasher = Sha512::new();
-        hasher.update(&data[..]);
-        let hash = hasher.finalize();
-
-        let mut rng = rand::thread_rng();
-        let signing_key = SigningKey::<Sha256>::new(private_key);
-        let sig = signing_key.sign_with_rng(&mut rng, &hash);
+        Self::new_signed(source, payload, cost, validity, &private_key)
+    }
 
-        Self {
+    fn new_signed(
+        source: AccountId,
+        payload: TxPayload<Operation>,
+        cost: u64,
+        validity: Validity,
+        private_key: &PrivateKey,
+    ) -> Self {
+        let mut tx = Self {
             source,
             payload,
-            signature: sig.to_vec().into(),
+            cost,
+            validity,
+            authorization: Authorization::Signature(Bytes::new()),
+        };
+
+        tx.authorization = Authorization::Signature(sign(private_key, &tx.signed_data()));
+        tx
+    }
+
+    /// Everything but the authorization is signed, so that it cannot be changed by someone else
+    ///
+    /// This includes the source, so a signature of a multisig signer cannot be reused
+    /// for another account that has the same signer.
+    fn signed_data(&self) -> Vec<u8> {
+        bincode::serialize(&(&self.source, &self.payload, self.cost, &self.validity)).unwrap()
+    }
+
+    /// Adds the signature of one of the signers of a multisig transaction
+    ///
+    /// Fails if the transaction was created with a single signature.
+    pub fn add_signature(
+        &mut self,
+        signer: AccountId,
+        private_key: &PrivateKey,
+    ) -> Result<(), Error> {
+        let data = self.signed_data();
+
+        match &mut self.authorization {
+            Authorization::Multisig(signatures) => {
+                signatures.push((signer, sign(private_key, &data)));
+                Ok(())
+            }
+            Authorization::Signature(_) => Err(Error::NotMultisig),
+        }
+    }
+
+    /// Checks that the transaction was signed by the given key
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        match &self.authorization {
+            Authorization::Signature(signature) => {
+                verify(public_key, &self.signed_data(), signature)
+            }
+            Authorization::Multisig(_) => false,
         }
     }
 
+    /// Checks that the given signer of a multisig transaction signed it with the given key
+    pub fn verify_signer(&self, signer: &AccountId, public_key: &PublicKey) -> bool {
+        let Authorization::Multisig(signatures) = &self.authorization else {
+            return false;
+        };
+
+        let data = self.signed_data();
+
+        signatures
+            .iter()
+            .any(|(account, signature)| account == signer && verify(public_key, &data, signature))
+    }
+
+    pub fn is_multisig(&self) -> bool {
+        matches!(self.authorization, Authorization::Multisig(_))
+    }
+
+    /// Number of bytes this transaction takes up in an epoch
+    pub fn byte_size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+
+    /// Uniquely identifies this transaction (signatures are randomized)
+    pub fn get_id(&self) -> TransactionId {
+        let data = bincode::serialize(self).unwrap();
+        Sha256::digest(&data).into()
+    }
+
     pub fn get_source(&self) -> &AccountId {
         &self.source
     }
 
+    pub fn get_cost(&self) -> u64 {
+        self.cost
+    }
+
+    pub fn get_validity(&self) -> &Validity {
+        &self.validity
+    }
+
     pub fn get_payload(&self) -> &TxPayload<Operation> {
         &self.payload
     }
@@ -73,3 +327,28 @@ impl<Operation: Serialize + Debug> Transaction<Operation> {
         self.payload
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{TimeBound, Validity};
+    use crate::protocol::RejectReason;
+
+    #[test]
+    fn validity_window() {
+        let validity = Validity {
+            not_before: Some(TimeBound::Epoch(2)),
+            not_after: Some(TimeBound::Timestamp(100)),
+        };
+
+        assert_eq!(validity.check(1, 0), Err(RejectReason::NotYetValid));
+        assert_eq!(validity.check(2, 100), Ok(()));
+        assert_eq!(validity.check(5, 101), Err(RejectReason::Expired));
+
+        assert_eq!(Validity::until_epoch(3).check(3, 0), Ok(()));
+        assert_eq!(
+            Validity::until_epoch(3).check(4, 0),
+            Err(RejectReason::Expired)
+        );
+        assert_eq!(Validity::default().check(u32::MAX, i64::MAX), Ok(()));
+    }
+}
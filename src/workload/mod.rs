@@ -0,0 +1,552 @@
+//! Client-side building blocks for benchmarks and workload drivers
+//!
+//! `WorkloadClient` submits transactions and measures how long it takes until they are
+//! confirmed, i.e., until the server sends the corresponding `Message::LedgerUpdate`.
+
+mod presigned;
+pub use presigned::{
+    cached_accounts, load_accounts, presign_transactions, save_accounts, TransactionReader,
+    TransactionWriter,
+};
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::encoding::{write_handshake, Encoding, WireCodec};
+use crate::protocol::{BatchItemResult, Message};
+use crate::transactions::{Transaction, TransactionId};
+use crate::{generate_key_pair, to_account_id, AccountId, Error, OpTrait, PrivateKey};
+
+/// An account whose key is known to the client
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: AccountId,
+    pub private_key: PrivateKey,
+}
+
+impl Account {
+    pub fn generate() -> Self {
+        let (private_key, public_key) = generate_key_pair();
+
+        Self {
+            id: to_account_id(&public_key),
+            private_key,
+        }
+    }
+}
+
+/// Generates keys for the given number of accounts, using all cores
+///
+/// Key generation is slow, so this should happen before a measurement starts.
+pub fn generate_accounts(num_accounts: usize) -> Vec<Account> {
+    parallel_map((0..num_accounts).collect(), |_| Account::generate())
+}
+
+/// Applies `func` to all items using one thread per core, preserving their order
+///
+/// Useful to sign many transactions up front.
+pub fn parallel_map<T: Send, R: Send>(items: Vec<T>, func: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let num_threads = std::thread::available_parallelism()
+        .map(|num| num.get())
+        .unwrap_or(1);
+    let chunk_size = items.len().div_ceil(num_threads).max(1);
+
+    let mut chunks = Vec::new();
+    let mut items = items.into_iter();
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+
+    let func = &func;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(func).collect::<Vec<_>>()))
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Transactions a client is waiting for and the results for those it is not waiting for anymore
+#[derive(Default)]
+struct Tracker {
+    pending: HashMap<TransactionId, Instant>,
+    /// The transactions of all batches the server has not reported results for yet
+    batches: VecDeque<Vec<TransactionId>>,
+    latencies: Vec<Duration>,
+    submitted: usize,
+    rejected: usize,
+    first_submission: Option<Instant>,
+    last_confirmation: Option<Instant>,
+    /// Once set, pending transactions will never be confirmed
+    disconnected: bool,
+}
+
+type ClientWriter = FramedWrite<WriteHalf<Box<dyn ClientStream>>, WireCodec>;
+
+trait ClientStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ClientStream for S {}
+
+/// A connection that submits transactions and tracks when they are confirmed
+pub struct WorkloadClient<OpType: OpTrait> {
+    encoding: Encoding,
+    writer: Mutex<ClientWriter>,
+    tracker: Arc<watch::Sender<Tracker>>,
+    _marker: PhantomData<OpType>,
+}
+
+impl<OpType: OpTrait + DeserializeOwned> WorkloadClient<OpType> {
+    /// Connects to a server via TCP
+    pub async fn connect(addr: SocketAddr, encoding: Encoding) -> Result<Self, Error> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        write_handshake(&mut stream, encoding).await?;
+        Ok(Self::from_stream(stream, encoding))
+    }
+
+    /// Uses a stream for which the handshake has already been sent
+    pub fn from_stream(
+        stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        encoding: Encoding,
+    ) -> Self {
+        let stream: Box<dyn ClientStream> = Box::new(stream);
+        let (read_half, write_half) = tokio::io::split(stream);
+
+        let tracker = Arc::new(watch::Sender::new(Tracker::default()));
+        let reader = FramedRead::new(read_half, WireCodec::new(encoding));
+
+        tokio::spawn(track_confirmations::<OpType, _>(
+            reader,
+            encoding,
+            tracker.clone(),
+        ));
+
+        Self {
+            encoding,
+            writer: Mutex::new(FramedWrite::new(write_half, WireCodec::new(encoding))),
+            tracker,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sends a transaction; its latency is measured from this call
+    pub async fn submit(&self, transaction: Transaction<OpType>) -> Result<(), Error> {
+        let id = transaction.get_id();
+        let data = self
+            .encoding
+            .encode(&Message::TransactionRequest { transaction })?;
+
+        self.send_tracked(data, vec![id], false).await
+    }
+
+    /// Sends multiple transactions in one `Message::TransactionBatch`
+    ///
+    /// Transactions the server discards or rejects are counted as rejected once it reports
+    /// the results of the batch.
+    pub async fn submit_batch(&self, transactions: Vec<Transaction<OpType>>) -> Result<(), Error> {
+        let ids = transactions.iter().map(|tx| tx.get_id()).collect();
+        let data = self
+            .encoding
+            .encode(&Message::TransactionBatch { transactions })?;
+
+        self.send_tracked(data, ids, true).await
+    }
+
+    async fn send_tracked(
+        &self,
+        data: Vec<u8>,
+        ids: Vec<TransactionId>,
+        is_batch: bool,
+    ) -> Result<(), Error> {
+        // Hold the writer while tracking, so batches are tracked in the order they are sent
+        let mut writer = self.writer.lock().await;
+
+        // Track the transactions before sending, so their confirmations cannot overtake us
+        let now = Instant::now();
+        self.tracker.send_modify(|tracker| {
+            for id in &ids {
+                tracker.pending.insert(*id, now);
+            }
+            tracker.submitted += ids.len();
+            tracker.first_submission.get_or_insert(now);
+
+            if is_batch {
+                tracker.batches.push_back(ids.clone());
+            }
+        });
+
+        if let Err(err) = writer.send(data.into()).await {
+            self.tracker.send_modify(|tracker| {
+                for id in &ids {
+                    tracker.pending.remove(id);
+                }
+                tracker.submitted -= ids.len();
+
+                if is_batch {
+                    tracker.batches.pop_back();
+                }
+            });
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// The number of transactions that were submitted but neither confirmed nor rejected
+    pub fn num_pending(&self) -> usize {
+        self.tracker.borrow().pending.len()
+    }
+
+    /// Waits until fewer than `limit` transactions are pending (or the connection was lost)
+    pub async fn wait_for_pending_below(&self, limit: usize) {
+        let mut tracker = self.tracker.subscribe();
+
+        // The sender lives as long as this client
+        let _ = tracker
+            .wait_for(|tracker| tracker.pending.len() < limit || tracker.disconnected)
+            .await;
+    }
+
+    /// Waits until all submitted transactions were confirmed or rejected
+    ///
+    /// Returns false if that did not happen within the given time.
+    pub async fn wait_for_all(&self, timeout: Duration) -> bool {
+        let wait = self.wait_for_pending_below(1);
+
+        tokio::time::timeout(timeout, wait).await.is_ok() && self.num_pending() == 0
+    }
+
+    /// Results for all transactions submitted so far
+    pub fn summary(&self) -> Summary {
+        let tracker = self.tracker.borrow();
+
+        let mut summary = Summary {
+            submitted: tracker.submitted,
+            rejected: tracker.rejected,
+            latencies: tracker.latencies.clone(),
+            first_submission: tracker.first_submission,
+            last_confirmation: tracker.last_confirmation,
+        };
+
+        summary.latencies.sort();
+        summary
+    }
+}
+
+/// Matches all messages from the server against the pending transactions
+///
+/// Once the connection is lost, the client is marked as disconnected.
+async fn track_confirmations<OpType, R>(
+    mut reader: FramedRead<R, WireCodec>,
+    encoding: Encoding,
+    tracker: Arc<watch::Sender<Tracker>>,
+) where
+    OpType: OpTrait + DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    while let Some(result) = reader.next().await {
+        let Ok(msg) = result
+            .map_err(Error::from)
+            .and_then(|data| encoding.decode(&data))
+        else {
+            break;
+        };
+
+        let now = Instant::now();
+
+        match msg {
+            Message::<OpType>::LedgerUpdate { transaction, .. } => {
+                let id = transaction.get_id();
+
+                // Ignore transactions of other clients
+                tracker.send_if_modified(|tracker| {
+                    let Some(submitted) = tracker.pending.remove(&id) else {
+                        return false;
+                    };
+
+                    tracker.latencies.push(now - submitted);
+                    tracker.last_confirmation = Some(now);
+                    true
+                });
+            }
+            Message::TransactionRejected { id, .. } => {
+                tracker.send_if_modified(|tracker| {
+                    if tracker.pending.remove(&id).is_none() {
+                        return false;
+                    }
+
+                    tracker.rejected += 1;
+                    true
+                });
+            }
+            Message::TransactionBatchResult { results } => {
+                tracker.send_modify(|tracker| {
+                    let Some(ids) = tracker.batches.pop_front() else {
+                        return;
+                    };
+
+                    // Committed transactions are confirmed by their LedgerUpdate
+                    for (id, result) in ids.iter().zip(results) {
+                        if result != BatchItemResult::Committed
+                            && tracker.pending.remove(id).is_some()
+                        {
+                            tracker.rejected += 1;
+                        }
+                    }
+                });
+            }
+            // Workload clients do not support authentication
+            Message::AuthChallenge { .. } => break,
+            _ => {}
+        }
+    }
+
+    tracker.send_modify(|tracker| tracker.disconnected = true);
+}
+
+/// Throughput and latency of a workload
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub submitted: usize,
+    pub rejected: usize,
+    /// Confirmation latencies in ascending order
+    pub latencies: Vec<Duration>,
+    first_submission: Option<Instant>,
+    last_confirmation: Option<Instant>,
+}
+
+impl Summary {
+    /// Combines the results of multiple clients that ran concurrently
+    pub fn merge(summaries: impl IntoIterator<Item = Summary>) -> Self {
+        let mut result = Self::default();
+
+        for summary in summaries {
+            result.submitted += summary.submitted;
+            result.rejected += summary.rejected;
+            result.latencies.extend(summary.latencies);
+
+            result.first_submission = match (result.first_submission, summary.first_submission) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            result.last_confirmation = result.last_confirmation.max(summary.last_confirmation);
+        }
+
+        result.latencies.sort();
+        result
+    }
+
+    /// Time from the first submission until the last confirmation
+    pub fn elapsed(&self) -> Duration {
+        match (self.first_submission, self.last_confirmation) {
+            (Some(first), Some(last)) => last.saturating_duration_since(first),
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn num_confirmed(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Transactions that were neither confirmed nor rejected
+    pub fn num_unconfirmed(&self) -> usize {
+        self.submitted - self.num_confirmed() - self.rejected
+    }
+
+    /// Confirmed transactions per second
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.elapsed();
+
+        if elapsed.is_zero() {
+            return 0.0;
+        }
+
+        self.num_confirmed() as f64 / elapsed.as_secs_f64()
+    }
+
+    /// The latency below which the given fraction (between 0 and 1) of confirmations fall
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+
+        let rank = (fraction * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Submitted {} transactions: {} confirmed, {} rejected, {} unconfirmed",
+            self.submitted,
+            self.num_confirmed(),
+            self.rejected,
+            self.num_unconfirmed()
+        )?;
+        writeln!(
+            f,
+            "Throughput: {:.1} tx/s over {:.3}s",
+            self.throughput(),
+            self.elapsed().as_secs_f64()
+        )?;
+
+        if self.latencies.is_empty() {
+            return Ok(());
+        }
+
+        let ms = |fraction| self.percentile(fraction).unwrap().as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "Confirmation latency (ms): p50={:.2} p90={:.2} p99={:.2} p99.9={:.2} max={:.2}",
+            ms(0.5),
+            ms(0.9),
+            ms(0.99),
+            ms(0.999),
+            ms(1.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{parallel_map, Summary};
+
+    #[test]
+    fn percentiles() {
+        let start = Instant::now();
+
+        let summary = Summary::merge([
+            Summary {
+                submitted: 60,
+                rejected: 5,
+                latencies: (1..=50).map(Duration::from_millis).collect(),
+                first_submission: Some(start + Duration::from_secs(1)),
+                last_confirmation: Some(start + Duration::from_secs(2)),
+            },
+            Summary {
+                submitted: 50,
+                rejected: 0,
+                latencies: (51..=100).rev().map(Duration::from_millis).collect(),
+                first_submission: Some(start),
+                last_confirmation: Some(start + Duration::from_secs(1)),
+            },
+        ]);
+
+        assert_eq!(summary.num_confirmed(), 100);
+        assert_eq!(summary.num_unconfirmed(), 5);
+        assert_eq!(summary.throughput(), 50.0);
+
+        assert_eq!(summary.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(summary.percentile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(summary.percentile(0.99), Some(Duration::from_millis(99)));
+        assert_eq!(summary.percentile(1.0), Some(Duration::from_millis(100)));
+        assert_eq!(Summary::default().percentile(0.5), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn confirmations() {
+        use std::sync::Arc;
+
+        use super::{generate_accounts, WorkloadClient};
+        use crate::encoding::Encoding;
+        use crate::server::{start_server, NullCallback, ServerConfig};
+        use crate::{TestOperation, TimeBound, Transaction, Validity};
+
+        let num_transactions = 10;
+        let encoding = Encoding::Cbor;
+
+        let config = ServerConfig::<TestOperation>::builder(Arc::new(NullCallback {}))
+            .in_memory()
+            .throughput(10_000.0)
+            .latency_ms(20)
+            .build()
+            .unwrap();
+        let server = start_server(config).await.unwrap();
+
+        let stream = server.connect(encoding).await.unwrap().into_inner();
+        let client = WorkloadClient::<TestOperation>::from_stream(stream, encoding);
+
+        let accounts = generate_accounts(2);
+        for pos in 0..num_transactions {
+            let account = &accounts[pos % accounts.len()];
+            let transaction = Transaction::new(
+                account.id,
+                TestOperation::Empty {},
+                account.private_key.clone(),
+            );
+
+            client.submit(transaction).await.unwrap();
+        }
+
+        // The same number of transactions again as a batch, one of which gets rejected
+        let mut batch: Vec<_> = (0..num_transactions)
+            .map(|pos| {
+                let account = &accounts[pos % accounts.len()];
+                Transaction::new(
+                    account.id,
+                    TestOperation::Empty {},
+                    account.private_key.clone(),
+                )
+            })
+            .collect();
+        let not_yet_valid = Validity {
+            not_before: Some(TimeBound::Epoch(5)),
+            not_after: None,
+        };
+        batch[1] = Transaction::new_with_validity(
+            accounts[1].id,
+            TestOperation::Empty {},
+            1,
+            not_yet_valid,
+            accounts[1].private_key.clone(),
+        );
+        client.submit_batch(batch).await.unwrap();
+
+        assert!(client.wait_for_all(Duration::from_secs(10)).await);
+        server.shutdown().await.unwrap();
+
+        let summary = client.summary();
+        assert_eq!(summary.submitted, 2 * num_transactions);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.num_confirmed(), 2 * num_transactions - 1);
+        assert!(summary.percentile(0.0).unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let result = parallel_map((0..1000).collect(), |num: u32| num * 2);
+        assert_eq!(result, (0..1000).map(|num| num * 2).collect::<Vec<_>>());
+    }
+}
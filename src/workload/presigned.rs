@@ -0,0 +1,212 @@
+//! Keys and signed transactions that are generated ahead of time
+//!
+//! Generating RSA keys and signing transactions is slow enough to make clients the bottleneck
+//! of a benchmark. Both can be done once, written to disk, and then loaded by the workload
+//! drivers. All files are sequences of bincode-encoded records.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::protocol::Message;
+use crate::transactions::Transaction;
+use crate::workload::{generate_accounts, parallel_map, Account};
+use crate::{Error, OpTrait};
+
+/// Reads the next record, or returns None at the end of the file
+fn read_record<T: DeserializeOwned>(reader: &mut impl Read) -> Option<Result<T, Error>> {
+    match bincode::deserialize_from(reader) {
+        Ok(record) => Some(Ok(record)),
+        Err(err) => match *err {
+            bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+            err => Some(Err(Error::Deserialization(err.to_string()))),
+        },
+    }
+}
+
+/// Writes the accounts (including their private keys) to a file
+pub fn save_accounts(path: &Path, accounts: &[Account]) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    for account in accounts {
+        bincode::serialize_into(&mut writer, account)
+            .map_err(|err| Error::Serialization(err.to_string()))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load_accounts(path: &Path) -> Result<Vec<Account>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    std::iter::from_fn(|| read_record(&mut reader)).collect()
+}
+
+/// Loads `num_accounts` accounts from the cache at `path`
+///
+/// If the cache does not exist or holds fewer accounts, the missing ones are generated
+/// and the cache is updated.
+pub fn cached_accounts(path: &Path, num_accounts: usize) -> Result<Vec<Account>, Error> {
+    let mut accounts = if path.exists() {
+        load_accounts(path)?
+    } else {
+        vec![]
+    };
+
+    if accounts.len() < num_accounts {
+        accounts.extend(generate_accounts(num_accounts - accounts.len()));
+        save_accounts(path, &accounts)?;
+    }
+
+    accounts.truncate(num_accounts);
+    Ok(accounts)
+}
+
+/// Writes signed transactions to a file, to be read by `TransactionReader`
+pub struct TransactionWriter {
+    writer: BufWriter<File>,
+}
+
+impl TransactionWriter {
+    /// Creates the file (or truncates it if it already exists)
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn append<OpType: OpTrait>(
+        &mut self,
+        transaction: &Transaction<OpType>,
+    ) -> Result<(), Error> {
+        bincode::serialize_into(&mut self.writer, transaction)
+            .map_err(|err| Error::Serialization(err.to_string()))
+    }
+
+    /// Must be called once all transactions have been appended
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Streams the transactions of a file written by `TransactionWriter`
+///
+/// Transactions are read lazily, so files do not have to fit into memory.
+pub struct TransactionReader<OpType: OpTrait> {
+    reader: BufReader<File>,
+    _marker: PhantomData<OpType>,
+}
+
+impl<OpType: OpTrait + DeserializeOwned> TransactionReader<OpType> {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Wraps every transaction into a `Message::TransactionRequest`
+    pub fn into_requests(self) -> impl Iterator<Item = Result<Message<OpType>, Error>> {
+        self.map(|result| result.map(|transaction| Message::TransactionRequest { transaction }))
+    }
+}
+
+impl<OpType: OpTrait + DeserializeOwned> Iterator for TransactionReader<OpType> {
+    type Item = Result<Transaction<OpType>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_record(&mut self.reader)
+    }
+}
+
+/// Signs `num_transactions` transactions, issued by the accounts in turn, and writes them to a file
+///
+/// Transactions are signed in parallel, a chunk at a time, so memory usage stays bounded.
+pub fn presign_transactions<OpType: OpTrait + Serialize>(
+    path: &Path,
+    accounts: &[Account],
+    num_transactions: usize,
+    make_operation: impl Fn(usize) -> OpType + Sync,
+) -> Result<(), Error> {
+    const CHUNK_SIZE: usize = 10_000;
+
+    assert!(!accounts.is_empty(), "Need at least one account");
+
+    let mut writer = TransactionWriter::create(path)?;
+
+    for start in (0..num_transactions).step_by(CHUNK_SIZE) {
+        let end = (start + CHUNK_SIZE).min(num_transactions);
+
+        let transactions = parallel_map((start..end).collect(), |pos| {
+            let account = &accounts[pos % accounts.len()];
+            Transaction::new(account.id, make_operation(pos), account.private_key.clone())
+        });
+
+        for transaction in &transactions {
+            writer.append(transaction)?;
+        }
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cached_accounts, load_accounts, presign_transactions, TransactionReader};
+    use crate::protocol::Message;
+    use crate::{sign, verify, TestOperation};
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir();
+        let keys_path = dir.join(format!("keys-{}.bin", std::process::id()));
+        let txs_path = dir.join(format!("presigned-{}.bin", std::process::id()));
+
+        let accounts = cached_accounts(&keys_path, 2).unwrap();
+        assert_eq!(load_accounts(&keys_path).unwrap().len(), 2);
+
+        // Existing keys are reused
+        let more_accounts = cached_accounts(&keys_path, 3).unwrap();
+        assert_eq!(more_accounts[1].id, accounts[1].id);
+        assert_eq!(load_accounts(&keys_path).unwrap().len(), 3);
+        assert_eq!(
+            cached_accounts(&keys_path, 1).unwrap()[0].id,
+            accounts[0].id
+        );
+
+        presign_transactions(&txs_path, &more_accounts, 7, |_| TestOperation::Empty {}).unwrap();
+
+        let requests: Vec<_> = TransactionReader::<TestOperation>::open(&txs_path)
+            .unwrap()
+            .into_requests()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        std::fs::remove_file(&keys_path).unwrap();
+        std::fs::remove_file(&txs_path).unwrap();
+
+        assert_eq!(requests.len(), 7);
+
+        for (pos, request) in requests.iter().enumerate() {
+            let Message::TransactionRequest { transaction } = request else {
+                panic!("Unexpected message: {request:?}");
+            };
+
+            let account = &more_accounts[pos % more_accounts.len()];
+            assert_eq!(*transaction.get_source(), account.id);
+        }
+
+        // The stored keys still work
+        let signature = sign(&more_accounts[2].private_key, b"test");
+        assert!(verify(
+            &more_accounts[2].private_key.to_public_key(),
+            b"test",
+            &signature
+        ));
+    }
+}